@@ -0,0 +1,143 @@
+//! Render throughput while templates are reloaded continuously.
+//!
+//! Compares `HandlebarsEngine`, which swaps `Arc` snapshots of the
+//! registry, with the previous design, where renders take a read lock on
+//! a `RwLock<Box<Handlebars>>` and reload holds the write lock while
+//! templates are parsed.
+//!
+//! Run with `cargo bench --bench concurrent_reload`.
+
+extern crate handlebars_iron as hbsi;
+
+use hbsi::handlebars::Handlebars;
+use hbsi::{DirectorySource, HandlebarsEngine, Source};
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, RwLock};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const READERS: usize = 4;
+const DURATION: Duration = Duration::from_secs(3);
+
+fn data() -> BTreeMap<String, String> {
+    let mut data = BTreeMap::new();
+    data.insert("year".to_owned(), "2015".to_owned());
+    data.insert("engine".to_owned(), "serde_json".to_owned());
+    data
+}
+
+fn source() -> DirectorySource {
+    DirectorySource::new("./examples/templates/", ".hbs")
+}
+
+struct Outcome {
+    renders: f64,
+    reloads: f64,
+    max_latency: Duration,
+}
+
+/// run `render` on reader threads and `reload` on another thread
+fn run<R, L>(render: R, reload: L) -> Outcome
+where
+    R: Fn() + Send + Sync + 'static,
+    L: Fn() + Send + 'static,
+{
+    let render = Arc::new(render);
+    let stop = Arc::new(AtomicBool::new(false));
+    let renders = Arc::new(AtomicUsize::new(0));
+    let max_latency = Arc::new(AtomicUsize::new(0));
+
+    let readers: Vec<_> = (0..READERS)
+        .map(|_| {
+            let (render, stop) = (render.clone(), stop.clone());
+            let (renders, max_latency) = (renders.clone(), max_latency.clone());
+            thread::spawn(move || {
+                while !stop.load(Ordering::Relaxed) {
+                    let start = Instant::now();
+                    render();
+                    let latency = start.elapsed().as_nanos() as usize;
+                    renders.fetch_add(1, Ordering::Relaxed);
+                    max_latency.fetch_max(latency, Ordering::Relaxed);
+                }
+            })
+        })
+        .collect();
+
+    let reloader = {
+        let stop = stop.clone();
+        thread::spawn(move || {
+            let mut reloads = 0;
+            while !stop.load(Ordering::Relaxed) {
+                reload();
+                reloads += 1;
+            }
+            reloads
+        })
+    };
+
+    let start = Instant::now();
+    thread::sleep(DURATION);
+    stop.store(true, Ordering::Relaxed);
+    for r in readers {
+        r.join().unwrap();
+    }
+    let reloads = reloader.join().unwrap();
+    let secs = start.elapsed().as_secs_f64();
+
+    Outcome {
+        renders: renders.load(Ordering::Relaxed) as f64 / secs,
+        reloads: reloads as f64 / secs,
+        max_latency: Duration::from_nanos(max_latency.load(Ordering::Relaxed) as u64),
+    }
+}
+
+fn bench_rwlock() -> Outcome {
+    let registry = Arc::new(RwLock::new(Box::new(Handlebars::new())));
+    let src = source();
+    src.load(&mut registry.write().unwrap()).unwrap();
+
+    let reader = registry.clone();
+    let data = data();
+    run(
+        move || {
+            reader.read().unwrap().render("index", &data).unwrap();
+        },
+        move || {
+            let mut hbs = registry.write().unwrap();
+            hbs.clear_templates();
+            src.load(&mut hbs).unwrap();
+        },
+    )
+}
+
+fn bench_arc_swap() -> Outcome {
+    let mut hbse = HandlebarsEngine::new();
+    hbse.add(Box::new(source()));
+    hbse.reload().unwrap();
+
+    let hbse = Arc::new(hbse);
+    let reader = hbse.clone();
+    let data = data();
+    run(
+        move || {
+            reader.registry().render("index", &data).unwrap();
+        },
+        move || {
+            hbse.reload().unwrap();
+        },
+    )
+}
+
+fn main() {
+    for &(name, bench) in &[
+        ("RwLock<Box<Handlebars>>", bench_rwlock as fn() -> Outcome),
+        ("ArcSwap<Handlebars>", bench_arc_swap),
+    ] {
+        let outcome = bench();
+        println!(
+            "{:<24} {:>10.0} renders/s {:>8.0} reloads/s  max render latency {:?}",
+            name, outcome.renders, outcome.reloads, outcome.max_latency
+        );
+    }
+}
@@ -33,7 +33,7 @@ mod data {
     pub fn make_data() -> Map<String, Value> {
         let mut data = Map::new();
 
-        data.insert("year".to_string(), to_json(&"2015".to_owned()));
+        data.insert("year".to_string(), to_json("2015".to_owned()));
 
         let teams = vec![
             Team {
@@ -55,7 +55,7 @@ mod data {
         ];
 
         data.insert("teams".to_string(), to_json(&teams));
-        data.insert("engine".to_string(), to_json(&"serde_json".to_owned()));
+        data.insert("engine".to_string(), to_json("serde_json".to_owned()));
         data
     }
 }
@@ -100,7 +100,7 @@ pub struct GzMiddleware;
 impl AfterMiddleware for GzMiddleware {
     fn after(&self, _: &mut Request, mut resp: Response) -> IronResult<Response> {
         let compressed_bytes = resp.body.as_mut().map(|b| {
-            let mut encoder = GzEncoder::new(Vec::new(), Compression::Best);
+            let mut encoder = GzEncoder::new(Vec::new(), Compression::best());
             {
                 let _ = b.write_body(&mut encoder);
             }
@@ -1,7 +1,7 @@
 extern crate handlebars_iron as hbs;
 extern crate iron;
 
-use hbs::{DirectorySource, HandlebarsEngine, Template};
+use hbs::{DirectorySource, HandlebarsEngine, RenderFailure, RenderFailureKind, Template};
 use iron::prelude::*;
 use iron::{status, AfterMiddleware};
 
@@ -19,7 +19,12 @@ struct ErrorReporter;
 
 impl AfterMiddleware for ErrorReporter {
     fn catch(&self, _: &mut Request, err: IronError) -> IronResult<Response> {
-        println!("{}", err);
+        match err.error.downcast_ref::<RenderFailure>() {
+            Some(f) if f.kind == RenderFailureKind::MissingTemplate => {
+                println!("Missing template: {:?}", f.template)
+            }
+            _ => println!("{}", err),
+        }
         Err(err)
     }
 }
@@ -31,6 +36,7 @@ fn main() {
         "./examples/templates/",
         ".hbs",
     )));
+    hbse.set_failure_status(RenderFailureKind::MissingTemplate, status::NotFound);
     // success of panic
     if let Err(r) = hbse.reload() {
         panic!("{}", r);
@@ -28,7 +28,7 @@ mod data {
     pub fn make_data() -> Map<String, Value> {
         let mut data = Map::new();
 
-        data.insert("year".to_string(), to_json(&"2015".to_owned()));
+        data.insert("year".to_string(), to_json("2015".to_owned()));
 
         let teams = vec![
             Team {
@@ -50,7 +50,7 @@ mod data {
         ];
 
         data.insert("teams".to_string(), to_json(&teams));
-        data.insert("engine".to_string(), to_json(&"serde_json".to_owned()));
+        data.insert("engine".to_string(), to_json("serde_json".to_owned()));
         data
     }
 }
@@ -123,6 +123,16 @@ fn main() {
         ),
     );
 
+    // expose request path to every template as `request.path`
+    hbse.add_context_provider(
+        "request",
+        Box::new(|req: &Request| {
+            let mut m = serde_json::value::Map::new();
+            m.insert("path".to_owned(), req.url.path().join("/").into());
+            serde_json::Value::Object(m)
+        }),
+    );
+
     let mut router = Router::new();
     router
         .get("/", index, "index")
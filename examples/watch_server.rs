@@ -7,11 +7,13 @@ extern crate serde_json;
 #[macro_use]
 extern crate serde_derive;
 extern crate env_logger;
+extern crate router;
 
 #[cfg(feature = "watch")]
 use hbs::Watchable;
 use hbs::{DirectorySource, HandlebarsEngine};
 use iron::prelude::*;
+use router::Router;
 
 use std::sync::Arc;
 
@@ -74,18 +76,24 @@ fn main() {
     use data::*;
     env_logger::init().unwrap();
 
-    let mut chain = Chain::new(hello_world);
-
     let mut hbse = HandlebarsEngine::new();
     let source = Box::new(DirectorySource::new("./examples/templates/", ".hbs"));
     hbse.add(source);
+    // pages reload in the browser when templates change
+    hbse.set_live_reload(Some("/live-reload"));
     if let Err(r) = hbse.reload() {
         panic!("{}", r);
     }
 
     let hbse_ref = Arc::new(hbse);
-    hbse_ref.watch("./examples/templates/");
+    hbse_ref.watch_sources().expect("failed to watch templates");
 
+    let mut router = Router::new();
+    router.get("/", hello_world, "index");
+    if let Some(live_reload) = hbse_ref.live_reload_handler() {
+        router.get("/live-reload", live_reload, "live-reload");
+    }
+    let mut chain = Chain::new(router);
     chain.link_after(hbse_ref);
 
     println!("Server running at http://localhost:3000/");
@@ -0,0 +1,81 @@
+//! Procedural macro behind `handlebars_iron::embed_templates!`, use that
+//! one instead of this crate.
+
+extern crate proc_macro;
+
+use std::env;
+use std::path::{Path, PathBuf};
+
+use proc_macro::TokenStream;
+use quote::quote;
+use syn::parse::Parser;
+use syn::punctuated::Punctuated;
+use syn::{LitStr, Token};
+use walkdir::WalkDir;
+
+/// expands to `&[(name, include_str!(path))]` for templates in directory
+/// `dir`, relative to the manifest of the calling crate, with `suffix`
+#[doc(hidden)]
+#[proc_macro]
+pub fn embedded_templates(input: TokenStream) -> TokenStream {
+    let args = match Punctuated::<LitStr, Token![,]>::parse_terminated.parse(input) {
+        Ok(args) => args,
+        Err(e) => return e.to_compile_error().into(),
+    };
+    let args: Vec<LitStr> = args.into_iter().collect();
+    if args.len() != 2 {
+        return quote!(compile_error!(
+            "expected template directory and suffix, like `\"templates\", \".hbs\"`"
+        ))
+        .into();
+    }
+
+    let manifest_dir = env::var("CARGO_MANIFEST_DIR").unwrap_or_default();
+    let dir = Path::new(&manifest_dir).join(args[0].value());
+    if !dir.is_dir() {
+        let msg = format!("template directory {} not found", dir.display());
+        return syn::Error::new(args[0].span(), msg)
+            .to_compile_error()
+            .into();
+    }
+
+    let entries = templates(&dir, &args[1].value())
+        .into_iter()
+        .map(|(name, path)| {
+            let path = path.to_string_lossy().into_owned();
+            quote!((#name, include_str!(#path)))
+        });
+    quote!(&[#(#entries),*]).into()
+}
+
+/// template names and paths in `dir`, following the naming rules of
+/// `DirectorySource::templates`
+fn templates(dir: &Path, suffix: &str) -> Vec<(String, PathBuf)> {
+    let mut templates: Vec<(String, PathBuf)> = WalkDir::new(dir)
+        .min_depth(1)
+        .into_iter()
+        .filter_map(|e| e.ok().map(|e| e.into_path()))
+        .filter(|path| path.to_string_lossy().ends_with(suffix))
+        .filter(|path| {
+            path.file_stem()
+                .map(|stem| stem.to_string_lossy())
+                .is_some_and(|stem| !(stem.starts_with('.') || stem.starts_with('#')))
+        })
+        .filter_map(|path| {
+            let name = path
+                .strip_prefix(dir)
+                .ok()?
+                .components()
+                .map(|c| c.as_os_str().to_string_lossy())
+                .collect::<Vec<_>>()
+                .join("/");
+            let name = name
+                .strip_suffix(suffix)
+                .map(|s| s.to_owned())
+                .unwrap_or(name);
+            Some((name, path))
+        })
+        .collect();
+    templates.sort();
+    templates
+}
@@ -0,0 +1,63 @@
+use iron::prelude::*;
+use serde_json::value::Value as Json;
+
+/// Provides request-scoped data to every rendered `Template`
+///
+/// Providers are registered with `HandlebarsEngine::add_context_provider`
+/// under a key. When the middleware renders a `Template`, value returned
+/// by each provider is inserted into template data with that key.
+///
+/// Data set by the handler always takes precedence: a provider is skipped
+/// when its key already exists in template data. Among providers, the one
+/// added later wins. Template data that is not a JSON object is left
+/// as is.
+pub trait ContextProvider {
+    fn provide(&self, req: &Request) -> Json;
+}
+
+impl<F> ContextProvider for F
+where
+    F: Fn(&Request) -> Json,
+{
+    fn provide(&self, req: &Request) -> Json {
+        (*self)(req)
+    }
+}
+
+/// merge provided values into template data, see `ContextProvider` for
+/// the precedence rule
+pub(crate) fn merge(value: &mut Json, provided: Vec<(&String, Json)>) {
+    if let Json::Object(ref mut m) = *value {
+        for (key, v) in provided.into_iter().rev() {
+            if !m.contains_key(key) {
+                m.insert(key.clone(), v);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::merge;
+    use serde_json::value::Value as Json;
+
+    #[test]
+    fn test_merge_precedence() {
+        let user = "user".to_owned();
+        let site = "site".to_owned();
+        let mut value = json!({"user": "from handler"});
+        merge(
+            &mut value,
+            vec![
+                (&user, json!("from provider")),
+                (&site, json!("first")),
+                (&site, json!("second")),
+            ],
+        );
+        assert_eq!(value, json!({"user": "from handler", "site": "second"}));
+
+        let mut value = Json::String("plain".to_owned());
+        merge(&mut value, vec![(&user, json!("from provider"))]);
+        assert_eq!(value, Json::String("plain".to_owned()));
+    }
+}
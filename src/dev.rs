@@ -0,0 +1,104 @@
+use std::error::Error;
+
+use error::RenderFailure;
+use handlebars::html_escape;
+use serde_json;
+use serde_json::value::Value as Json;
+
+/// lines shown before and after the failing line
+const EXCERPT_CONTEXT: usize = 3;
+
+/// Information shown on the developer error page
+pub(crate) struct ErrorPageInfo<'a> {
+    pub failure: &'a RenderFailure,
+    pub source: Option<String>,
+    pub data: &'a Json,
+    pub registered: Option<Vec<String>>,
+}
+
+fn excerpt(source: &str, line: usize, column: Option<usize>) -> String {
+    let first = line.saturating_sub(EXCERPT_CONTEXT).max(1);
+    let mut out = String::new();
+    for (no, text) in source
+        .lines()
+        .enumerate()
+        .map(|(i, l)| (i + 1, l))
+        .skip(first - 1)
+        .take(line + EXCERPT_CONTEXT + 1 - first)
+    {
+        if no == line {
+            out.push_str(&format!("<mark>{:>4} | {}</mark>\n", no, html_escape(text)));
+            if let Some(col) = column {
+                out.push_str(&format!("       {}^\n", " ".repeat(col.saturating_sub(1))));
+            }
+        } else {
+            out.push_str(&format!("{:>4} | {}\n", no, html_escape(text)));
+        }
+    }
+    out
+}
+
+/// render the developer error page for a failed template
+pub(crate) fn error_page(info: &ErrorPageInfo) -> String {
+    let error = &info.failure.cause;
+    let template = info
+        .failure
+        .template
+        .as_deref()
+        .unwrap_or("inline template");
+
+    let mut message = error.to_string();
+    let mut cause = error.source();
+    while let Some(e) = cause {
+        message.push_str(&format!("\ncaused by: {}", e));
+        cause = e.source();
+    }
+
+    let mut out = format!(
+        "<!DOCTYPE html>\n<html>\n<head><title>Template error: {name}</title></head>\n<body>\n\
+         <h1>Failed to render {name}</h1>\n<pre>{message}</pre>\n",
+        name = html_escape(template),
+        message = html_escape(&message)
+    );
+
+    if let (Some(ref source), Some(line)) = (&info.source, error.line_no) {
+        let name = error.template_name.as_deref().unwrap_or(template);
+        out.push_str(&format!(
+            "<h2>{} line {}</h2>\n<pre>{}</pre>\n",
+            html_escape(name),
+            line,
+            excerpt(source, line, error.column_no)
+        ));
+    }
+
+    if let Some(ref registered) = info.registered {
+        out.push_str("<h2>Registered templates</h2>\n<ul>\n");
+        for name in registered {
+            out.push_str(&format!("<li>{}</li>\n", html_escape(name)));
+        }
+        out.push_str("</ul>\n");
+    }
+
+    out.push_str(&format!(
+        "<h2>Data</h2>\n<pre>{}</pre>\n</body>\n</html>\n",
+        html_escape(&serde_json::to_string_pretty(info.data).unwrap_or_default())
+    ));
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use super::excerpt;
+
+    #[test]
+    fn test_excerpt() {
+        let source = "1\n2\n3\n4\n5\n6\n7\n8\n9";
+        let e = excerpt(source, 5, Some(2));
+        assert_eq!(e.lines().count(), 8);
+        assert!(e.starts_with("   2 | 2\n"));
+        assert!(e.contains("<mark>   5 | 5</mark>\n        ^\n"));
+        assert!(e.ends_with("   8 | 8\n"));
+
+        assert_eq!(excerpt(source, 1, None).lines().count(), 4);
+    }
+}
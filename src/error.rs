@@ -0,0 +1,97 @@
+use std::error::Error;
+use std::fmt;
+
+use handlebars::{RenderError, TemplateError};
+
+/// Kind of a `RenderFailure`
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum RenderFailureKind {
+    /// the template, or its layout, is not registered
+    MissingTemplate,
+    /// the inline template of `Template::with` failed to parse
+    InvalidTemplate,
+    /// rendering failed, for example in a helper or in strict mode
+    Render,
+}
+
+/// Error of a failed render, reported by `HandlebarsEngine` as the error
+/// of an `IronError`
+///
+/// Downstream middlewares can get it by downcasting:
+///
+/// ```ignore
+/// if let Some(failure) = err.error.downcast_ref::<RenderFailure>() {
+///     if failure.kind == RenderFailureKind::MissingTemplate { ... }
+/// }
+/// ```
+#[derive(Debug)]
+pub struct RenderFailure {
+    /// name of the template, `None` for inline templates of `Template::with`
+    pub template: Option<String>,
+    pub kind: RenderFailureKind,
+    pub cause: RenderError,
+}
+
+impl RenderFailure {
+    pub(crate) fn new(template: Option<String>, cause: RenderError) -> RenderFailure {
+        // handlebars reports missing templates with this description only
+        let kind = if cause.desc.starts_with("Template not found: ") {
+            RenderFailureKind::MissingTemplate
+        } else if cause
+            .source()
+            .is_some_and(|c| c.downcast_ref::<TemplateError>().is_some())
+        {
+            RenderFailureKind::InvalidTemplate
+        } else {
+            RenderFailureKind::Render
+        };
+        RenderFailure {
+            template,
+            kind,
+            cause,
+        }
+    }
+}
+
+impl fmt::Display for RenderFailure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match self.template {
+            Some(ref name) => write!(f, "Failed to render template {}: {}", name, self.cause),
+            None => write!(f, "Failed to render inline template: {}", self.cause),
+        }
+    }
+}
+
+impl Error for RenderFailure {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        Some(&self.cause)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use handlebars::Handlebars;
+
+    #[test]
+    fn test_kind() {
+        let hbs = Handlebars::new();
+        let e = hbs.render_template("{{#if}}", &()).unwrap_err();
+        assert_eq!(
+            RenderFailure::new(None, e).kind,
+            RenderFailureKind::InvalidTemplate
+        );
+
+        let e = hbs.render("index", &()).unwrap_err();
+        let failure = RenderFailure::new(Some("index".to_owned()), e);
+        assert_eq!(failure.kind, RenderFailureKind::MissingTemplate);
+        assert!(failure
+            .to_string()
+            .starts_with("Failed to render template index"));
+
+        let mut hbs = Handlebars::new();
+        hbs.set_strict_mode(true);
+        let e = hbs.render_template("{{missing}}", &()).unwrap_err();
+        assert_eq!(RenderFailure::new(None, e).kind, RenderFailureKind::Render);
+    }
+}
@@ -0,0 +1,62 @@
+use std::collections::HashMap;
+
+use iron::status::Status;
+
+/// Templates rendered for error responses without a `Template`
+///
+/// When a handler returns an `IronError` whose response has no `Template`,
+/// `HandlebarsEngine` looks up a template by status code, or the fallback
+/// template, and renders it with data:
+///
+/// * `status`: the status code, like `404`
+/// * `reason`: the reason phrase, like `Not Found`
+/// * `error`: description of the error, only in dev mode, see
+///   `HandlebarsEngine::set_dev_mode`
+///
+/// If the error page fails to render, a plain text page with status and
+/// reason is sent instead.
+#[derive(Clone, Debug, Default)]
+pub struct ErrorPages {
+    pages: HashMap<u16, String>,
+    fallback: Option<String>,
+}
+
+impl ErrorPages {
+    /// create an empty error page configuration
+    pub fn new() -> ErrorPages {
+        ErrorPages::default()
+    }
+
+    /// render `template` for errors with `status`
+    pub fn page(mut self, status: Status, template: &str) -> ErrorPages {
+        self.pages.insert(status.to_u16(), template.to_owned());
+        self
+    }
+
+    /// render `template` for errors without a page of their own status
+    pub fn fallback(mut self, template: &str) -> ErrorPages {
+        self.fallback = Some(template.to_owned());
+        self
+    }
+
+    /// template name for given status
+    pub fn template_for(&self, status: Status) -> Option<&String> {
+        self.pages.get(&status.to_u16()).or(self.fallback.as_ref())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::ErrorPages;
+    use iron::status;
+
+    #[test]
+    fn test_template_for() {
+        let pages = ErrorPages::new().page(status::NotFound, "404");
+        assert_eq!(pages.template_for(status::NotFound).unwrap(), "404");
+        assert!(pages.template_for(status::BadRequest).is_none());
+
+        let pages = pages.fallback("error");
+        assert_eq!(pages.template_for(status::BadRequest).unwrap(), "error");
+    }
+}
@@ -33,28 +33,78 @@
 
 pub extern crate handlebars;
 
+extern crate arc_swap;
 extern crate iron;
 
 extern crate serde;
+#[cfg_attr(test, macro_use)]
 extern crate serde_json;
 
+#[cfg(feature = "archive")]
+extern crate flate2;
+#[cfg(feature = "embed")]
+extern crate handlebars_iron_macros;
 #[cfg(feature = "watch")]
 extern crate notify;
 extern crate plugin;
+#[cfg(feature = "sqlite")]
+extern crate rusqlite;
+#[cfg(all(unix, feature = "sighup"))]
+extern crate signal_hook;
+#[cfg(feature = "archive")]
+extern crate tar;
+extern crate walkdir;
+#[cfg(feature = "archive")]
+extern crate zip;
 
 #[macro_use]
 extern crate log;
 
-pub use self::middleware::HandlebarsEngine;
+pub use self::context::ContextProvider;
+pub use self::error::{RenderFailure, RenderFailureKind};
+pub use self::error_pages::ErrorPages;
+pub use self::live_reload::LiveReloadHandler;
 pub use self::middleware::Template;
-pub use self::source::{Source, SourceError};
+pub use self::middleware::{HandlebarsEngine, RegistryWriteGuard};
+pub use self::negotiation::JsonNegotiation;
+pub use self::poll::{PollHandle, Pollable};
+pub use self::reload::{
+    Collision, CollisionPolicy, ReloadEvent, ReloadReport, SourceReport, TemplateFailure,
+};
+#[cfg(all(unix, feature = "sighup"))]
+pub use self::sighup::{SighupHandle, SighupReloadable};
+pub use self::source::{Fingerprint, Source, SourceError, WatchTarget};
+#[cfg(feature = "archive")]
+pub use self::sources::archive::ArchiveSource;
 pub use self::sources::directory::DirectorySource;
+pub use self::sources::embedded::EmbeddedSource;
 pub use self::sources::memory::MemorySource;
+pub use self::sources::overlay::OverlaySource;
+pub use self::sources::prefixed::Prefixed;
+#[cfg(feature = "sqlite")]
+pub use self::sources::sqlite::SqliteSource;
+pub use self::types::{Escape, ExtensionTypes};
 #[cfg(feature = "watch")]
-pub use self::watch::Watchable;
+pub use self::watch::{WatchHandle, WatchMode, WatchOptions, Watchable};
+#[cfg(feature = "embed")]
+#[doc(hidden)]
+pub use handlebars_iron_macros::embedded_templates as __embedded_templates;
+#[cfg(feature = "watch")]
+pub use notify::Error as WatchError;
 
+mod context;
+mod dev;
+mod error;
+mod error_pages;
+mod live_reload;
 mod middleware;
+mod negotiation;
+mod poll;
+mod reload;
+#[cfg(all(unix, feature = "sighup"))]
+mod sighup;
 mod source;
 mod sources;
+mod types;
 #[cfg(feature = "watch")]
 mod watch;
@@ -0,0 +1,273 @@
+use std::io::{self, Write};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, PoisonError};
+use std::time::Duration;
+
+use iron::headers::{CacheControl, CacheDirective, ContentType};
+use iron::mime::{Mime, SubLevel, TopLevel};
+use iron::prelude::*;
+use iron::response::WriteBody;
+use iron::{status, Handler};
+
+use reload::ReloadEvent;
+
+/// how often an idle event stream sends a comment, to notice closed
+/// connections
+const KEEP_ALIVE: Duration = Duration::from_secs(15);
+
+/// Outcome of the last reload
+#[derive(Clone, Default)]
+struct Outcome {
+    /// bumped on every reload, successful or not
+    version: u64,
+    /// generation of the registry, see `HandlebarsEngine::generation`
+    generation: u64,
+    /// report of the last reload, if it failed
+    failure: Option<String>,
+}
+
+/// Reload state shared by the engine and live reload handlers, see
+/// `HandlebarsEngine::set_live_reload`
+pub(crate) struct LiveReload {
+    path: String,
+    outcome: Mutex<Outcome>,
+    changed: Condvar,
+}
+
+impl LiveReload {
+    pub(crate) fn new(path: &str) -> LiveReload {
+        LiveReload {
+            path: path.to_owned(),
+            outcome: Mutex::new(Outcome::default()),
+            changed: Condvar::new(),
+        }
+    }
+
+    fn outcome(&self) -> MutexGuard<'_, Outcome> {
+        self.outcome.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// record the result of a reload and wake up event streams
+    ///
+    /// Events older than the last one are ignored, and so are reloads
+    /// that changed nothing unless they clear a failure, so pages aren't
+    /// reloaded on every poll.
+    pub(crate) fn reloaded(&self, event: &ReloadEvent) {
+        let mut outcome = self.outcome();
+        if event.generation < outcome.generation {
+            return;
+        }
+        if event.result.is_ok() && event.changed.is_empty() && outcome.failure.is_none() {
+            return;
+        }
+        outcome.version += 1;
+        outcome.generation = event.generation;
+        outcome.failure = match event.result {
+            Ok(_) => None,
+            Err(ref report) => Some(report.to_string()),
+        };
+        self.changed.notify_all();
+    }
+
+    /// outcome after `version`, or the current one after `timeout`
+    fn wait(&self, version: u64, timeout: Duration) -> Outcome {
+        let outcome = self.outcome();
+        let (outcome, _) = self
+            .changed
+            .wait_timeout_while(outcome, timeout, |o| o.version == version)
+            .unwrap_or_else(PoisonError::into_inner);
+        outcome.clone()
+    }
+
+    /// client script connecting to the event stream
+    pub(crate) fn script(&self) -> String {
+        format!(
+            "<script>(function(){{\
+             var s=new EventSource({url}),o;\
+             s.addEventListener(\"reload\",function(){{location.reload()}});\
+             s.addEventListener(\"failure\",function(e){{\
+             if(!o){{o=document.createElement(\"pre\");\
+             o.style.cssText=\"position:fixed;top:0;left:0;right:0;bottom:0;margin:0;\
+             padding:2em;overflow:auto;white-space:pre-wrap;z-index:2147483647;\
+             background:rgba(0,0,0,.85);color:#f88;font:14px monospace\";\
+             document.body.appendChild(o)}}\
+             o.textContent=e.data}})}})();</script>",
+            url = js_string(&format!(
+                "{}?since={}",
+                self.path,
+                self.outcome().generation
+            ))
+        )
+    }
+
+    /// insert the client script before `</body>` of an html page
+    pub(crate) fn inject(&self, page: &mut String) {
+        if let Some(at) = page.to_ascii_lowercase().rfind("</body>") {
+            page.insert_str(at, &self.script());
+        }
+    }
+}
+
+/// whether a response with this content type gets the client script
+pub(crate) fn is_html(content_type: Option<&ContentType>) -> bool {
+    matches!(
+        content_type,
+        Some(&ContentType(Mime(TopLevel::Text, SubLevel::Html, _)))
+    )
+}
+
+/// a javascript string literal of `s`, safe to embed in a script element
+fn js_string(s: &str) -> String {
+    let mut out = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' | '\\' | '<' | '>' | '&' | '\'' => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c if c.is_control() => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// Handler serving the live reload event stream, get one with
+/// `HandlebarsEngine::live_reload_handler` and mount it at the path
+/// given to `HandlebarsEngine::set_live_reload`
+///
+/// The stream is a Server-Sent Events stream with a `reload` event after
+/// every successful reload and a `failure` event with the reload report
+/// after a failed one. Each connection keeps a server thread busy, so
+/// this is for development only.
+pub struct LiveReloadHandler {
+    pub(crate) live: Arc<LiveReload>,
+}
+
+impl Handler for LiveReloadHandler {
+    fn handle(&self, req: &mut Request) -> IronResult<Response> {
+        let since = req.url.query().and_then(|q| {
+            q.split('&')
+                .filter_map(|p| p.strip_prefix("since="))
+                .find_map(|g| g.parse().ok())
+        });
+        let mut resp = Response::with(status::Ok);
+        resp.headers.set(ContentType(
+            "text/event-stream".parse().expect("valid mime"),
+        ));
+        resp.headers
+            .set(CacheControl(vec![CacheDirective::NoCache]));
+        resp.body = Some(Box::new(EventStream {
+            live: self.live.clone(),
+            since,
+        }));
+        Ok(resp)
+    }
+}
+
+/// Response body sending reload events until the client disconnects
+struct EventStream {
+    live: Arc<LiveReload>,
+    /// generation the page was rendered with
+    since: Option<u64>,
+}
+
+/// event telling clients about `outcome`
+fn event(outcome: &Outcome) -> String {
+    match outcome.failure {
+        Some(ref failure) => {
+            let mut event = "event: failure\n".to_owned();
+            for line in failure.lines() {
+                event.push_str(&format!("data: {}\n", line));
+            }
+            event + "\n"
+        }
+        None => format!("event: reload\ndata: {}\n\n", outcome.generation),
+    }
+}
+
+impl WriteBody for EventStream {
+    fn write_body(&mut self, res: &mut dyn Write) -> io::Result<()> {
+        let outcome = self.live.outcome().clone();
+        res.write_all(b"retry: 1000\n\n")?;
+        // the page may be older than the last reload, or broken
+        if outcome.failure.is_some() || self.since.is_some_and(|g| g != outcome.generation) {
+            res.write_all(event(&outcome).as_bytes())?;
+        }
+        res.flush()?;
+
+        let mut version = outcome.version;
+        loop {
+            let outcome = self.live.wait(version, KEEP_ALIVE);
+            if outcome.version == version {
+                res.write_all(b": keep-alive\n\n")?;
+            } else {
+                version = outcome.version;
+                res.write_all(event(&outcome).as_bytes())?;
+            }
+            res.flush()?;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use reload::ReloadReport;
+
+    #[test]
+    fn test_inject() {
+        let live = LiveReload::new("/live");
+        let mut page = "<html><BODY>hi</BODY></html>".to_owned();
+        live.inject(&mut page);
+        assert!(page.starts_with("<html><BODY>hi<script>"));
+        assert!(page.contains("\"/live?since=0\""));
+        assert!(page.ends_with("</script></BODY></html>"));
+
+        let mut fragment = "<p>hi</p>".to_owned();
+        live.inject(&mut fragment);
+        assert_eq!(fragment, "<p>hi</p>");
+
+        assert_eq!(
+            js_string("a\"</script>"),
+            "\"a\\u0022\\u003c/script\\u003e\""
+        );
+    }
+
+    fn event(generation: u64, ok: bool, changed: &[&str]) -> ReloadEvent {
+        ReloadEvent {
+            generation,
+            result: if ok {
+                Ok(ReloadReport::default())
+            } else {
+                Err(ReloadReport::default())
+            },
+            changed: changed.iter().map(|n| n.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn test_reloaded_in_order() {
+        let live = LiveReload::new("/live");
+        live.reloaded(&event(2, true, &["index"]));
+        live.reloaded(&event(1, true, &["index"]));
+        assert_eq!(live.outcome().generation, 2);
+        assert_eq!(live.outcome().version, 1);
+
+        live.reloaded(&event(2, false, &[]));
+        assert_eq!(live.outcome().generation, 2);
+        assert!(live.outcome().failure.is_some());
+    }
+
+    #[test]
+    fn test_reloaded_unchanged() {
+        let live = LiveReload::new("/live");
+        live.reloaded(&event(1, true, &["index"]));
+        live.reloaded(&event(1, true, &[]));
+        assert_eq!(live.outcome().version, 1);
+
+        // the failure overlay goes away even if templates are back as
+        // they were
+        live.reloaded(&event(1, false, &[]));
+        live.reloaded(&event(1, true, &[]));
+        assert_eq!(live.outcome().version, 3);
+        assert!(live.outcome().failure.is_none());
+    }
+}
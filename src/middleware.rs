@@ -1,23 +1,57 @@
-use std::sync::{RwLock, RwLockWriteGuard};
+use std::collections::BTreeSet;
+use std::collections::HashMap;
+use std::io::{self, BufWriter, Write};
+use std::mem;
+use std::ops::{Deref, DerefMut};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::thread;
+use std::time::Instant;
 
-use iron::headers::ContentType;
+use arc_swap::ArcSwap;
+use iron::headers::{ContentType, Header, HeaderFormat, Headers, Vary};
+use iron::mime::Mime;
 use iron::modifier::Modifier;
 use iron::prelude::*;
-use iron::status;
-use iron::{typemap, AfterMiddleware};
+use iron::response::WriteBody;
+use iron::status::{self, Status};
+use iron::{modifiers, typemap, AfterMiddleware};
 use plugin::Plugin as PluginFor;
 
-use handlebars::{to_json, Handlebars, TemplateRenderError};
+use handlebars::{self, to_json, Handlebars, RenderError};
 use serde::ser::Serialize as ToJson;
-use serde_json::value::Value as Json;
+use serde_json;
+use serde_json::value::{Map, Value as Json};
 
-use source::{Source, SourceError};
+use context::{self, ContextProvider};
+use dev;
+use error::{RenderFailure, RenderFailureKind};
+use error_pages::ErrorPages;
+use live_reload::{self, LiveReload, LiveReloadHandler};
+use negotiation::{self, JsonNegotiation};
+use reload::{self, Collision, CollisionPolicy, Ownership, ReloadEvent, ReloadReport, SourceState};
+use source::Source;
+use sources::prefixed::Prefixed;
+use types::{Escape, ExtensionTypes};
+
+#[derive(Clone)]
+enum Layout {
+    Default,
+    Named(String),
+    Disabled,
+}
 
 #[derive(Clone)]
 pub struct Template {
     name: Option<String>,
     content: Option<String>,
     value: Json,
+    layout: Layout,
+    json: bool,
+    strict: Option<bool>,
+    status: Option<Status>,
+    content_type: Option<Mime>,
+    headers: Headers,
 }
 
 impl Template {
@@ -27,6 +61,12 @@ impl Template {
             name: Some(name.to_string()),
             value: to_json(&value),
             content: None,
+            layout: Layout::Default,
+            json: true,
+            strict: None,
+            status: None,
+            content_type: None,
+            headers: Headers::new(),
         }
     }
 
@@ -36,14 +76,114 @@ impl Template {
             name: None,
             value: to_json(&value),
             content: Some(content.to_string()),
+            layout: Layout::Default,
+            json: true,
+            strict: None,
+            status: None,
+            content_type: None,
+            headers: Headers::new(),
+        }
+    }
+
+    /// render the page inside given layout template, instead of the
+    /// engine's default layout
+    pub fn layout(mut self, name: &str) -> Template {
+        self.layout = Layout::Named(name.to_string());
+        self
+    }
+
+    /// render the page without any layout
+    pub fn no_layout(mut self) -> Template {
+        self.layout = Layout::Disabled;
+        self
+    }
+
+    /// never serve the data of this template as JSON, even if the engine
+    /// has JSON negotiation enabled and client asks for it
+    pub fn no_json(mut self) -> Template {
+        self.json = false;
+        self
+    }
+
+    /// set response status, applied only if rendering succeeds
+    pub fn status(mut self, status: Status) -> Template {
+        self.status = Some(status);
+        self
+    }
+
+    /// set response content type, `text/html` is used when neither template
+    /// nor response has one
+    pub fn content_type(mut self, mime: Mime) -> Template {
+        self.content_type = Some(mime);
+        self
+    }
+
+    /// set a response header, applied only if rendering succeeds
+    pub fn header<H: Header + HeaderFormat>(mut self, header: H) -> Template {
+        self.headers.set(header);
+        self
+    }
+
+    /// enable or disable handlebars strict mode for this template,
+    /// regardless of registry setting
+    pub fn strict(mut self, enable: bool) -> Template {
+        self.strict = Some(enable);
+        self
+    }
+
+    /// apply response settings of this template to a successfully
+    /// rendered response, `default_type` is used when neither template nor
+    /// response has a content type
+    fn apply_to(&self, resp: &mut Response, default_type: Option<&Mime>) {
+        if let Some(status) = self.status {
+            resp.status = Some(status);
+        }
+        resp.headers.extend(self.headers.iter());
+        if let Some(ref mime) = self.content_type {
+            resp.headers.set(ContentType(mime.clone()));
+        } else if !resp.headers.has::<ContentType>() {
+            match default_type {
+                Some(mime) => resp.headers.set(ContentType(mime.clone())),
+                None => resp.headers.set(ContentType::html()),
+            }
         }
     }
 }
 
 /// The handlebars template engine
 pub struct HandlebarsEngine {
-    pub sources: Vec<Box<dyn Source + Send + Sync>>,
-    pub registry: RwLock<Box<Handlebars<'static>>>,
+    sources: Vec<Box<dyn Source + Send + Sync>>,
+    registry: ArcSwap<Handlebars<'static>>,
+    writer: Mutex<Vec<SourceState>>,
+    default_layout: Option<String>,
+    providers: Vec<(String, Box<dyn ContextProvider + Send + Sync>)>,
+    json_negotiation: Option<JsonNegotiation>,
+    streaming: bool,
+    variants: ArcSwap<VariantCache>,
+    extension_types: ExtensionTypes,
+    error_pages: Option<ErrorPages>,
+    dev_mode: bool,
+    failure_status: HashMap<RenderFailureKind, Status>,
+    reload_keep_going: bool,
+    collision_policy: CollisionPolicy,
+    ownership: ArcSwap<Ownership>,
+    live_reload: Option<Arc<LiveReload>>,
+    generation: AtomicU64,
+    reload_hooks: ArcSwap<Vec<ReloadHook>>,
+}
+
+/// callback for `HandlebarsEngine::on_reload`
+type ReloadHook = Arc<dyn Fn(&ReloadEvent) + Send + Sync>;
+
+/// registries derived for template settings, with the registry each one
+/// was derived from
+type VariantCache = HashMap<Variant, (Arc<Handlebars<'static>>, Arc<Handlebars<'static>>)>;
+
+/// Registry settings a `Template` can override for its own rendering
+#[derive(Clone, PartialEq, Eq, Hash)]
+struct Variant {
+    strict: bool,
+    escape: Escape,
 }
 
 impl typemap::Key for HandlebarsEngine {
@@ -70,77 +210,703 @@ impl PluginFor<Response> for HandlebarsEngine {
 impl HandlebarsEngine {
     /// create a handlebars template engine
     pub fn new() -> HandlebarsEngine {
-        HandlebarsEngine {
-            sources: Vec::new(),
-            registry: RwLock::new(Box::new(Handlebars::new())),
-        }
+        HandlebarsEngine::from(Handlebars::new())
     }
 
     /// create a handlebars template engine from existed handlebars registry
     pub fn from(reg: Handlebars<'static>) -> HandlebarsEngine {
         HandlebarsEngine {
             sources: Vec::new(),
-            registry: RwLock::new(Box::new(reg)),
+            registry: ArcSwap::from_pointee(reg),
+            writer: Mutex::new(Vec::new()),
+            default_layout: None,
+            providers: Vec::new(),
+            json_negotiation: None,
+            streaming: false,
+            variants: ArcSwap::from_pointee(HashMap::new()),
+            extension_types: ExtensionTypes::default(),
+            error_pages: None,
+            dev_mode: false,
+            failure_status: HashMap::new(),
+            reload_keep_going: false,
+            collision_policy: CollisionPolicy::default(),
+            ownership: ArcSwap::from_pointee(Ownership::default()),
+            live_reload: None,
+            generation: AtomicU64::new(0),
+            reload_hooks: ArcSwap::from_pointee(Vec::new()),
         }
     }
 
     /// add a template source
     pub fn add(&mut self, source: Box<dyn Source + Send + Sync>) {
         self.sources.push(source);
+        // templates of the last reload are kept by source index, start over
+        // with the next one
+        self.writer
+            .get_mut()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clear();
+    }
+
+    /// template sources, in the order they were added
+    pub fn sources(&self) -> &[Box<dyn Source + Send + Sync>] {
+        &self.sources
+    }
+
+    /// add a template source, whose templates are registered under
+    /// `prefix`, see `Prefixed`
+    pub fn add_with_prefix(&mut self, prefix: &str, source: Box<dyn Source + Send + Sync>) {
+        self.add(Box::new(Prefixed::boxed(prefix, source)));
     }
 
     /// load template from registered sources
-    pub fn reload(&self) -> Result<(), SourceError> {
-        let mut hbs = self.handlebars_mut();
+    ///
+    /// Templates are loaded into a copy of current registry, which keeps
+    /// registered helpers and settings. The copy replaces current registry
+    /// only when all sources are loaded successfully, otherwise current
+    /// templates are left untouched and the report of failed templates is
+    /// returned as error. See `set_reload_keep_going` to collect all
+    /// failures instead of stopping at the first one.
+    ///
+    /// Sources providing `Source::fingerprints` are reloaded incrementally,
+    /// only their changed templates are parsed again.
+    pub fn reload(&self) -> Result<ReloadReport, ReloadReport> {
+        self.reload_with(None)
+    }
+
+    /// load templates from sources at `indices`, in the order sources were
+    /// added, like `reload`
+    ///
+    /// Other sources keep the templates of the last successful reload, or
+    /// are loaded too if there was none.
+    pub fn reload_sources(&self, indices: &[usize]) -> Result<ReloadReport, ReloadReport> {
+        self.reload_with(Some(indices))
+    }
+
+    fn reload_with(&self, only: Option<&[usize]>) -> Result<ReloadReport, ReloadReport> {
+        let mut state = self.lock_writer();
+        let event = self.load_sources(&mut state, only);
+        // still holding the writer, so events are dispatched in the order
+        // of their generations
+        if let Some(ref live) = self.live_reload {
+            live.reloaded(&event);
+        }
+        for hook in self.reload_hooks.load().iter() {
+            hook(&event);
+        }
+        event.result
+    }
+
+    fn load_sources(&self, state: &mut Vec<SourceState>, only: Option<&[usize]>) -> ReloadEvent {
+        let start = Instant::now();
+        let before = self.registry();
+        let mut hbs = Handlebars::clone(&before);
         hbs.clear_templates();
-        for s in self.sources.iter() {
-            s.load(&mut hbs)?
+
+        // previous state is only meaningful for the same list of sources,
+        // it's cleared when a source is added
+        let previous = if state.len() == self.sources.len() {
+            Some(&*state)
+        } else {
+            None
+        };
+        let mut report = ReloadReport::default();
+        let mut loaded = Vec::with_capacity(self.sources.len());
+        for (i, s) in self.sources.iter().enumerate() {
+            let (source, source_report) = match previous.map(|p| &p[i]) {
+                Some(p) if only.is_some_and(|only| !only.contains(&i)) => reload::reuse(p),
+                previous => reload::load_source(s.as_ref(), previous, &hbs, self.reload_keep_going),
+            };
+            report.sources.push(source_report);
+            if source.is_none() && !self.reload_keep_going {
+                break;
+            }
+            loaded.push(source);
+        }
+        let ownership = reload::resolve(
+            &self.sources[..loaded.len()],
+            &loaded,
+            self.collision_policy,
+            &mut report,
+        );
+        report.elapsed = start.elapsed();
+
+        if !report.is_ok() {
+            return ReloadEvent {
+                generation: self.generation(),
+                result: Err(report),
+                changed: BTreeSet::new(),
+            };
+        }
+        let loaded: Vec<SourceState> = loaded.into_iter().flatten().collect();
+        for (name, &owner) in ownership.owners.iter() {
+            hbs.register_template(name, loaded[owner].templates[name].clone());
+        }
+        let changed = reload::changed_templates(&before, &hbs);
+        *state = loaded;
+        self.ownership.store(Arc::new(ownership));
+        // keep the registry when nothing changed, so derived registries
+        // stay cached while polling
+        let generation = if changed.is_empty() {
+            self.generation()
+        } else {
+            self.registry.store(Arc::new(hbs));
+            self.generation.fetch_add(1, Ordering::SeqCst) + 1
+        };
+        ReloadEvent {
+            generation,
+            result: Ok(report),
+            changed,
+        }
+    }
+
+    /// number of successful reloads that changed templates so far
+    pub fn generation(&self) -> u64 {
+        self.generation.load(Ordering::SeqCst)
+    }
+
+    /// call `hook` after every reload, successful or not, whether started
+    /// by `reload`, `reload_sources` or a watcher
+    ///
+    /// Hooks run on the thread that reloaded, after the new registry is in
+    /// place, one reload at a time and in the order of generations. Other
+    /// reloads and `handlebars_mut` wait for them, so hooks should return
+    /// quickly and must not call those themselves. The engine has no
+    /// channel of events, since reports can't be cloned; to handle reloads
+    /// elsewhere, forward what's needed through a channel:
+    ///
+    /// ```ignore
+    /// let (tx, rx) = mpsc::channel();
+    /// hbse.on_reload(move |event| {
+    ///     if event.result.is_ok() {
+    ///         let _ = tx.send(event.changed.clone());
+    ///     }
+    /// });
+    /// ```
+    pub fn on_reload<F>(&self, hook: F)
+    where
+        F: Fn(&ReloadEvent) + Send + Sync + 'static,
+    {
+        let hook: ReloadHook = Arc::new(hook);
+        self.reload_hooks.rcu(|hooks| {
+            let mut hooks = Vec::clone(hooks);
+            hooks.push(hook.clone());
+            hooks
+        });
+    }
+
+    /// keep loading templates after a failure on `reload`, to report all
+    /// broken templates at once
+    ///
+    /// The registry is still replaced only when every template loaded.
+    pub fn set_reload_keep_going(&mut self, enable: bool) {
+        self.reload_keep_going = enable;
+    }
+
+    /// choose what happens on `reload` when several sources provide a
+    /// template of the same name, `CollisionPolicy::WarnOverride` by
+    /// default
+    pub fn set_collision_policy(&mut self, policy: CollisionPolicy) {
+        self.collision_policy = policy;
+    }
+
+    /// index of the source providing template `name`, in the order
+    /// sources were added, as of the last successful reload
+    pub fn template_source(&self, name: &str) -> Option<usize> {
+        self.ownership.load().owners.get(name).cloned()
+    }
+
+    /// templates provided by several sources on the last successful
+    /// reload
+    pub fn collisions(&self) -> Vec<Collision> {
+        self.ownership.load().collisions.clone()
+    }
+
+    /// serialize writers of the registry, a panic in another writer
+    /// doesn't matter because the registry is only replaced as a whole
+    fn lock_writer(&self) -> MutexGuard<'_, Vec<SourceState>> {
+        self.writer.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// add a context provider, whose value is merged into data of every
+    /// rendered template under `key`
+    ///
+    /// Data set by the handler takes precedence over provided values, see
+    /// `ContextProvider` for details.
+    pub fn add_context_provider(
+        &mut self,
+        key: &str,
+        provider: Box<dyn ContextProvider + Send + Sync>,
+    ) {
+        self.providers.push((key.to_owned(), provider));
+    }
+
+    /// render templates for error responses, see `ErrorPages`
+    ///
+    /// Error pages are rendered in `catch` for errors whose response has
+    /// neither a `Template` nor a body.
+    pub fn set_error_pages(&mut self, pages: Option<ErrorPages>) {
+        self.error_pages = pages;
+    }
+
+    /// expose error details to clients, for development only
+    ///
+    /// In dev mode, a failed render responds with an error page showing
+    /// the handlebars error, an excerpt of the template source, the data
+    /// passed to the template and, for missing templates, all registered
+    /// template names. Error pages set by `set_error_pages` also get the
+    /// error description.
+    ///
+    /// Dev mode is disabled by default, and is only available in debug
+    /// builds: calling this in a release build logs a warning and leaves
+    /// dev mode disabled.
+    pub fn set_dev_mode(&mut self, enable: bool) {
+        if enable && !cfg!(debug_assertions) {
+            warn!("Dev mode is not available in release builds");
+            return;
+        }
+        self.dev_mode = enable;
+    }
+
+    /// reload pages in the browser when templates are reloaded, for
+    /// development only
+    ///
+    /// HTML pages rendered by this engine get a script inserted before
+    /// `</body>`, or appended when streaming, which listens to the event
+    /// stream at `path` and reloads the page after every successful
+    /// `reload` that changed templates. Failed reloads show their report
+    /// over the page instead.
+    /// Serve the event stream at `path` with `live_reload_handler`, and
+    /// trigger reloads with a watcher like `Watchable::watch_sources`.
+    ///
+    /// Like dev mode, live reload is only available in debug builds.
+    pub fn set_live_reload(&mut self, path: Option<&str>) {
+        if path.is_some() && !cfg!(debug_assertions) {
+            warn!("Live reload is not available in release builds");
+            return;
+        }
+        self.live_reload = path.map(|p| Arc::new(LiveReload::new(p)));
+    }
+
+    /// handler serving the live reload event stream, when enabled by
+    /// `set_live_reload`
+    pub fn live_reload_handler(&self) -> Option<LiveReloadHandler> {
+        self.live_reload
+            .as_ref()
+            .map(|live| LiveReloadHandler { live: live.clone() })
+    }
+
+    /// set response status for render failures of given kind, failures are
+    /// reported with `500 Internal Server Error` by default
+    pub fn set_failure_status(&mut self, kind: RenderFailureKind, status: Status) {
+        self.failure_status.insert(kind, status);
+    }
+
+    /// serve template data as JSON when client asks for it, see
+    /// `JsonNegotiation` for available checks
+    ///
+    /// Only data set by the handler is serialized, values from context
+    /// providers are not included.
+    pub fn set_json_negotiation(&mut self, negotiation: Option<JsonNegotiation>) {
+        self.json_negotiation = negotiation;
+    }
+
+    /// set a layout template for every page rendered by this engine
+    ///
+    /// The page is rendered first, and then the layout is rendered with the
+    /// same data plus the rendered page as `body`, which can be included
+    /// with `{{{body}}}`. A `Template` may choose another layout with
+    /// `Template::layout` or opt out with `Template::no_layout`.
+    pub fn set_default_layout(&mut self, name: Option<&str>) {
+        self.default_layout = name.map(|n| n.to_owned());
+    }
+
+    /// render template output directly into response body instead of
+    /// building the page in memory first
+    ///
+    /// Streamed response keeps using the registry it started with, even
+    /// if templates are reloaded in the meantime. Note that errors during
+    /// streaming can only be logged, because status and headers are
+    /// already sent. Missing templates and syntax errors of
+    /// `Template::with` content are still reported as errors before
+    /// streaming starts.
+    ///
+    /// Only the outermost template is streamed: with a layout, the page is
+    /// still rendered in memory first, and the layout is streamed with it
+    /// as `body`.
+    pub fn set_streaming(&mut self, enable: bool) {
+        self.streaming = enable;
+    }
+
+    fn can_stream(&self, hbs: &Handlebars<'static>, t: &Template) -> bool {
+        // make sure missing templates and broken content are reported
+        // before streaming starts
+        t.name.as_ref().is_none_or(|name| hbs.has_template(name))
+            && self.layout_of(t).is_none_or(|name| hbs.has_template(name))
+            && t.content
+                .as_ref()
+                .is_none_or(|content| handlebars::Template::compile(content).is_ok())
+    }
+
+    fn layout_of<'a>(&'a self, t: &'a Template) -> Option<&'a String> {
+        match t.layout {
+            Layout::Default => self.default_layout.as_ref(),
+            Layout::Named(ref name) => Some(name),
+            Layout::Disabled => None,
         }
-        Ok(())
+    }
+
+    fn render(
+        &self,
+        hbs: &Handlebars<'static>,
+        t: &Template,
+    ) -> Option<Result<String, RenderError>> {
+        let page = render_page(hbs, t)?;
+
+        match self.layout_of(t) {
+            Some(layout) => Some(page.and_then(|body| hbs.render(layout, &layout_data(t, body)))),
+            None => Some(page),
+        }
+    }
+
+    /// current registry
+    ///
+    /// The returned registry is a snapshot, it's not affected by later
+    /// `reload` or `handlebars_mut`. Getting it never blocks.
+    pub fn registry(&self) -> Arc<Handlebars<'static>> {
+        self.registry.load_full()
+    }
+
+    /// registry to render given template with, taking per-template settings
+    /// into account
+    fn registry_for(&self, t: &Template) -> Arc<Handlebars<'static>> {
+        let hbs = self.registry();
+        let variant = Variant {
+            strict: t.strict.unwrap_or_else(|| hbs.strict_mode()),
+            escape: self.type_of(t).map_or(Escape::Default, |&(_, e)| e),
+        };
+        if variant.strict == hbs.strict_mode() && variant.escape == Escape::Default {
+            return hbs;
+        }
+
+        if let Some((ref base, ref reg)) = self.variants.load().get(&variant) {
+            if Arc::ptr_eq(base, &hbs) {
+                return reg.clone();
+            }
+        }
+        let mut reg = Handlebars::clone(&hbs);
+        reg.set_strict_mode(variant.strict);
+        if let Some(f) = variant.escape.escape_fn() {
+            reg.register_escape_fn(f);
+        }
+        let reg = Arc::new(reg);
+        self.variants.rcu(|variants| {
+            let mut variants = VariantCache::clone(variants);
+            variants.insert(variant.clone(), (hbs.clone(), reg.clone()));
+            variants
+        });
+        reg
+    }
+
+    /// content type and escaping inferred from template name
+    fn type_of(&self, t: &Template) -> Option<&(Mime, Escape)> {
+        t.name
+            .as_ref()
+            .and_then(|name| self.extension_types.get(name))
+    }
+
+    /// table of template name extensions used to infer content type and
+    /// escaping of a template, see `ExtensionTypes`
+    pub fn extension_types_mut(&mut self) -> &mut ExtensionTypes {
+        &mut self.extension_types
     }
 
     /// access internal handlebars registry, useful to register custom helpers
-    pub fn handlebars_mut(&self) -> RwLockWriteGuard<Box<Handlebars<'static>>> {
-        self.registry.write().unwrap()
+    ///
+    /// Changes are made to a copy of the registry, which replaces the
+    /// current one when the returned guard is dropped. Taking the guard
+    /// clones the whole registry, templates included, so make all changes
+    /// through one guard rather than calling this for each of them. If the
+    /// thread panics while holding the guard, the changes are discarded.
+    pub fn handlebars_mut(&self) -> RegistryWriteGuard<'_> {
+        let writer = self.lock_writer();
+        RegistryWriteGuard {
+            engine: self,
+            registry: Handlebars::clone(&self.registry()),
+            _writer: writer,
+        }
     }
 }
 
-impl AfterMiddleware for HandlebarsEngine {
-    fn after(&self, _: &mut Request, mut resp: Response) -> IronResult<Response> {
-        let page_wrapper = resp.extensions.remove::<HandlebarsEngine>().and_then(|h| {
-            let hbs = self.registry.read().unwrap();
-            if let Some(ref name) = h.name {
-                Some(
-                    hbs.render(name, &h.value)
-                        .map_err(TemplateRenderError::from),
-                )
-            } else if let Some(ref content) = h.content {
-                Some(hbs.render_template(content, &h.value))
-            } else {
-                None
-            }
+impl HandlebarsEngine {
+    /// error for a failed render, with the developer error page as
+    /// response body in dev mode
+    fn render_failure(&self, hbs: &Handlebars<'static>, t: &Template, e: RenderError) -> IronError {
+        let failure = RenderFailure::new(t.name.clone(), e);
+        let status = self
+            .failure_status
+            .get(&failure.kind)
+            .cloned()
+            .unwrap_or(status::InternalServerError);
+        if !self.dev_mode {
+            return IronError::new(failure, status);
+        }
+
+        let registered = if failure.kind == RenderFailureKind::MissingTemplate {
+            let mut names: Vec<String> = hbs.get_templates().keys().cloned().collect();
+            names.sort();
+            Some(names)
+        } else {
+            None
+        };
+        let source = match failure.cause.template_name {
+            Some(ref name) => self.source_text(name),
+            None if t.name.is_none() => t.content.clone(),
+            None => None,
+        };
+
+        let mut page = dev::error_page(&dev::ErrorPageInfo {
+            failure: &failure,
+            source,
+            data: &t.value,
+            registered,
         });
+        if let Some(ref live) = self.live_reload {
+            live.inject(&mut page);
+        }
+        IronError::new(
+            failure,
+            (status, modifiers::Header(ContentType::html()), page),
+        )
+    }
+
+    /// text of a template from the source that registered it, if the
+    /// source supports it
+    fn source_text(&self, name: &str) -> Option<String> {
+        let source = self.template_source(name)?;
+        self.sources.get(source)?.source_text(name)
+    }
+
+    fn render_error_page(&self, req: &mut Request, err: &mut IronError, page: &str) -> Response {
+        let status = err.response.status.unwrap_or(status::InternalServerError);
+        let reason = status.canonical_reason().unwrap_or("");
+
+        let mut data = Map::new();
+        data.insert("status".to_owned(), Json::from(status.to_u16()));
+        data.insert("reason".to_owned(), Json::from(reason));
+        if self.dev_mode {
+            data.insert("error".to_owned(), Json::from(err.error.to_string()));
+        }
+
+        let mut resp = mem::replace(&mut err.response, Response::new());
+        let headers = resp.headers.clone();
+        resp.status = Some(status);
+        resp.set_mut(Template::new(page, data));
+        match self.after(req, resp) {
+            Ok(resp) => resp,
+            Err(e) => {
+                warn!("Failed to render error page {}: {}", page, e);
+                let mut resp = Response::with((status, format!("{} {}", status.to_u16(), reason)));
+                resp.headers.extend(headers.iter());
+                resp.headers.set(ContentType::plaintext());
+                resp
+            }
+        }
+    }
+}
+
+/// Write access to the registry of a `HandlebarsEngine`
+///
+/// Renders keep using the previous registry until the guard is dropped.
+pub struct RegistryWriteGuard<'a> {
+    engine: &'a HandlebarsEngine,
+    registry: Handlebars<'static>,
+    _writer: MutexGuard<'a, Vec<SourceState>>,
+}
+
+impl<'a> Deref for RegistryWriteGuard<'a> {
+    type Target = Handlebars<'static>;
+
+    fn deref(&self) -> &Handlebars<'static> {
+        &self.registry
+    }
+}
+
+impl<'a> DerefMut for RegistryWriteGuard<'a> {
+    fn deref_mut(&mut self) -> &mut Handlebars<'static> {
+        &mut self.registry
+    }
+}
+
+impl<'a> Drop for RegistryWriteGuard<'a> {
+    fn drop(&mut self) {
+        // changes may be half done
+        if thread::panicking() {
+            return;
+        }
+        let registry = mem::take(&mut self.registry);
+        self.engine.registry.store(Arc::new(registry));
+    }
+}
+
+fn render_page(hbs: &Handlebars<'static>, t: &Template) -> Option<Result<String, RenderError>> {
+    if let Some(ref name) = t.name {
+        Some(hbs.render(name, &t.value))
+    } else {
+        t.content
+            .as_ref()
+            .map(|content| hbs.render_template(content, &t.value))
+    }
+}
+
+fn layout_data(t: &Template, body: String) -> Map<String, Json> {
+    let mut data = match t.value {
+        Json::Object(ref m) => m.clone(),
+        _ => Map::new(),
+    };
+    data.insert("body".to_owned(), Json::String(body));
+    data
+}
+
+/// Response body that renders a template while it's written
+struct StreamingBody {
+    registry: Arc<Handlebars<'static>>,
+    template: Template,
+    layout: Option<String>,
+    /// live reload script appended to the page
+    script: Option<String>,
+}
+
+impl StreamingBody {
+    fn render_to<W: Write>(&self, w: W) -> Result<(), RenderError> {
+        let hbs = &self.registry;
+        let t = &self.template;
+        if let Some(ref layout) = self.layout {
+            let body = render_page(hbs, t).unwrap_or_else(|| Ok(String::new()))?;
+            hbs.render_to_write(layout, &layout_data(t, body), w)
+        } else if let Some(ref name) = t.name {
+            hbs.render_to_write(name, &t.value, w)
+        } else if let Some(ref content) = t.content {
+            hbs.render_template_to_write(content, &t.value, w)
+        } else {
+            Ok(())
+        }
+    }
+}
 
-        match page_wrapper {
-            Some(page_result) => match page_result {
-                Ok(page) => {
-                    if !resp.headers.has::<ContentType>() {
-                        resp.headers.set(ContentType::html());
+impl WriteBody for StreamingBody {
+    fn write_body(&mut self, res: &mut dyn Write) -> io::Result<()> {
+        let mut w = BufWriter::new(res);
+        if let Err(e) = self.render_to(&mut w) {
+            let failure = RenderFailure::new(self.template.name.clone(), e);
+            info!("{}", failure);
+            return Err(io::Error::other(failure));
+        }
+        if let Some(ref script) = self.script {
+            w.write_all(script.as_bytes())?;
+        }
+        w.flush()
+    }
+}
+
+impl Default for HandlebarsEngine {
+    fn default() -> HandlebarsEngine {
+        HandlebarsEngine::new()
+    }
+}
+
+impl AfterMiddleware for HandlebarsEngine {
+    fn after(&self, req: &mut Request, mut resp: Response) -> IronResult<Response> {
+        let mut h = match resp.extensions.remove::<HandlebarsEngine>() {
+            Some(h) => h,
+            None => return Ok(resp),
+        };
+
+        if let Some(ref negotiation) = self.json_negotiation {
+            if h.json {
+                if negotiation.accept_header {
+                    negotiation::vary_on_accept(&mut resp.headers);
+                    // a `Vary` set on the template replaces the response's one
+                    if h.headers.has::<Vary>() {
+                        negotiation::vary_on_accept(&mut h.headers);
                     }
-                    resp.set_mut(page);
-                    Ok(resp)
                 }
-                Err(e) => {
-                    info!("{}", e);
-                    Err(IronError::new(e, status::InternalServerError))
+                if negotiation.wants_json(req) {
+                    return match serde_json::to_string(&h.value) {
+                        Ok(body) => {
+                            h.apply_to(&mut resp, None);
+                            resp.headers.set(ContentType::json());
+                            resp.set_mut(body);
+                            Ok(resp)
+                        }
+                        Err(e) => Err(IronError::new(e, status::InternalServerError)),
+                    };
                 }
-            },
+            }
+        }
+
+        if !self.providers.is_empty() {
+            let provided = self
+                .providers
+                .iter()
+                .map(|(key, p)| (key, p.provide(req)))
+                .collect();
+            context::merge(&mut h.value, provided);
+        }
+
+        let hbs = self.registry_for(&h);
+        let mime = self.type_of(&h).map(|(m, _)| m.clone());
+        if self.streaming && self.can_stream(&hbs, &h) {
+            let layout = self.layout_of(&h).cloned();
+            h.apply_to(&mut resp, mime.as_ref());
+            let script = self
+                .live_reload
+                .as_ref()
+                .filter(|_| live_reload::is_html(resp.headers.get()))
+                .map(|live| live.script());
+            resp.body = Some(Box::new(StreamingBody {
+                registry: hbs,
+                template: h,
+                layout,
+                script,
+            }));
+            return Ok(resp);
+        }
+
+        match self.render(&hbs, &h) {
+            Some(Ok(mut page)) => {
+                h.apply_to(&mut resp, mime.as_ref());
+                if let Some(ref live) = self.live_reload {
+                    if live_reload::is_html(resp.headers.get()) {
+                        live.inject(&mut page);
+                    }
+                }
+                resp.set_mut(page);
+                Ok(resp)
+            }
+            Some(Err(e)) => {
+                info!("{}", e);
+                Err(self.render_failure(&hbs, &h, e))
+            }
             None => Ok(resp),
         }
     }
 
     fn catch(&self, req: &mut Request, mut err: IronError) -> IronResult<Response> {
-        err.response = self.after(req, err.response)?;
+        if err.response.extensions.contains::<HandlebarsEngine>() {
+            err.response = self.after(req, err.response)?;
+        } else if err.response.body.is_none() {
+            let page = self
+                .error_pages
+                .as_ref()
+                .and_then(|p| {
+                    p.template_for(err.response.status.unwrap_or(status::InternalServerError))
+                })
+                .cloned();
+            if let Some(page) = page {
+                err.response = self.render_error_page(req, &mut err, &page);
+            }
+        }
         Err(err)
     }
 }
@@ -150,7 +916,9 @@ mod test {
     use handlebars::{Context, Handlebars, Helper, Output, RenderContext, RenderError};
     use iron::prelude::*;
     use middleware::*;
+    use sources::memory::MemorySource;
     use std::collections::BTreeMap;
+    use std::panic::{self, AssertUnwindSafe};
 
     fn hello_world() -> IronResult<Response> {
         let resp = Response::new();
@@ -172,7 +940,7 @@ mod test {
 
     #[test]
     fn test_resp_set() {
-        let mut resp = hello_world().ok().expect("response expected");
+        let mut resp = hello_world().expect("response expected");
 
         // use response plugin to retrieve a cloned template for testing
         match resp.get::<HandlebarsEngine>() {
@@ -195,7 +963,7 @@ mod test {
 
     #[test]
     fn test_resp_set2() {
-        let mut resp = hello_world2().ok().expect("response expected");
+        let mut resp = hello_world2().expect("response expected");
 
         // use response plugin to retrieve a cloned template for testing
         match resp.get::<HandlebarsEngine>() {
@@ -216,6 +984,123 @@ mod test {
         }
     }
 
+    #[test]
+    fn test_render_with_layout() {
+        let mut templates = BTreeMap::new();
+        templates.insert("layout".to_owned(), "<p>{{{body}}}</p>".to_owned());
+        templates.insert("index".to_owned(), "{{title}}".to_owned());
+
+        let mut hbse = HandlebarsEngine::new();
+        hbse.add(Box::new(MemorySource(templates)));
+        hbse.set_default_layout(Some("layout"));
+        hbse.reload().unwrap();
+
+        let mut data = BTreeMap::new();
+        data.insert("title".to_owned(), "Handlebars on Iron".to_owned());
+
+        let hbs = hbse.registry();
+        let page = |t: Template| hbse.render(&hbs, &t).unwrap().unwrap();
+        assert_eq!(
+            page(Template::new("index", &data)),
+            "<p>Handlebars on Iron</p>"
+        );
+        assert_eq!(
+            page(Template::with("<b>{{title}}</b>", &data)),
+            "<p><b>Handlebars on Iron</b></p>"
+        );
+        assert_eq!(
+            page(Template::new("index", &data).no_layout()),
+            "Handlebars on Iron"
+        );
+    }
+
+    #[test]
+    fn test_streaming_body_keeps_registry() {
+        let mut templates = BTreeMap::new();
+        templates.insert("layout".to_owned(), "<p>{{{body}}}</p>".to_owned());
+        templates.insert("index".to_owned(), "{{title}}".to_owned());
+
+        let mut hbse = HandlebarsEngine::new();
+        hbse.add(Box::new(MemorySource(templates)));
+        hbse.reload().unwrap();
+
+        let mut data = BTreeMap::new();
+        data.insert("title".to_owned(), "Handlebars on Iron".to_owned());
+
+        let mut body = StreamingBody {
+            registry: hbse.registry(),
+            template: Template::new("index", &data),
+            layout: Some("layout".to_owned()),
+            script: None,
+        };
+
+        hbse.sources.clear();
+        hbse.reload().unwrap();
+        assert!(!hbse.registry().has_template("index"));
+
+        let mut out = Vec::new();
+        body.write_body(&mut out).unwrap();
+        assert_eq!(out, b"<p>Handlebars on Iron</p>");
+    }
+
+    #[test]
+    fn test_template_builder() {
+        let hbse = HandlebarsEngine::new();
+        let data: BTreeMap<String, String> = BTreeMap::new();
+
+        let t = Template::with("{{title}}", &data).strict(true);
+        let strict = hbse.registry_for(&t);
+        assert!(strict.strict_mode());
+        assert!(hbse.render(&strict, &t).unwrap().is_err());
+        assert!(Arc::ptr_eq(&strict, &hbse.registry_for(&t)));
+        assert!(!hbse.registry().strict_mode());
+
+        let t = Template::with("{{title}}", &data)
+            .status(status::NotFound)
+            .content_type("text/plain; charset=utf-8".parse().unwrap())
+            .header(Vary::Any);
+        let mut resp = Response::new();
+        t.apply_to(&mut resp, None);
+        assert_eq!(resp.status, Some(status::NotFound));
+        assert_eq!(
+            resp.headers.get::<ContentType>(),
+            Some(&ContentType::plaintext())
+        );
+        assert!(resp.headers.has::<Vary>());
+    }
+
+    #[test]
+    fn test_extension_types() {
+        let mut templates = BTreeMap::new();
+        templates.insert("feed.xml".to_owned(), "<t>{{title}}</t>".to_owned());
+        templates.insert("index".to_owned(), "<p>{{title}}</p>".to_owned());
+
+        let mut hbse = HandlebarsEngine::new();
+        hbse.add(Box::new(MemorySource(templates)));
+        hbse.reload().unwrap();
+
+        let mut data = BTreeMap::new();
+        data.insert("title".to_owned(), "'Iron'".to_owned());
+
+        let t = Template::new("feed.xml", &data);
+        let hbs = hbse.registry_for(&t);
+        assert_eq!(
+            hbse.render(&hbs, &t).unwrap().unwrap(),
+            "<t>&apos;Iron&apos;</t>"
+        );
+        assert_eq!(
+            hbse.type_of(&t).unwrap().0,
+            "application/xml; charset=utf-8".parse::<Mime>().unwrap()
+        );
+
+        let t = Template::new("index", &data);
+        assert!(hbse.type_of(&t).is_none());
+        assert_eq!(
+            hbse.render(&hbse.registry_for(&t), &t).unwrap().unwrap(),
+            "<p>&#x27;Iron&#x27;</p>"
+        );
+    }
+
     #[test]
     fn test_register_helper() {
         let hbs = HandlebarsEngine::new();
@@ -232,4 +1117,23 @@ mod test {
             ),
         );
     }
+
+    #[test]
+    fn test_registry_write_guard_panic() {
+        let hbse = HandlebarsEngine::new();
+        hbse.handlebars_mut()
+            .register_template_string("index", "kept")
+            .unwrap();
+
+        let panicked = panic::catch_unwind(AssertUnwindSafe(|| {
+            let mut reg = hbse.handlebars_mut();
+            reg.unregister_template("index");
+            panic!("half done");
+        }));
+        assert!(panicked.is_err());
+        assert_eq!(hbse.registry().render("index", &()).unwrap(), "kept");
+
+        hbse.handlebars_mut().unregister_template("index");
+        assert!(!hbse.registry().has_template("index"));
+    }
 }
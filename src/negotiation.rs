@@ -0,0 +1,162 @@
+use iron::headers::{Accept, Headers, QualityItem};
+use iron::mime::{Mime, SubLevel, TopLevel};
+use iron::prelude::*;
+
+/// Serve `Template` data as JSON when the client asks for it
+///
+/// Enabled with `HandlebarsEngine::set_json_negotiation`. When any of the
+/// configured checks matches a request, the data of its `Template` is
+/// serialized as `application/json` instead of rendering the template.
+/// A `Template` can opt out with `Template::no_json`.
+#[derive(Clone, Debug)]
+pub struct JsonNegotiation {
+    /// serve JSON when `Accept` header prefers `application/json` over html
+    pub accept_header: bool,
+    /// serve JSON when request path ends with `.json`
+    pub path_suffix: bool,
+    /// serve JSON when this query parameter is set to `json`,
+    /// for example `format` for `?format=json`
+    pub query_param: Option<String>,
+}
+
+impl Default for JsonNegotiation {
+    fn default() -> JsonNegotiation {
+        JsonNegotiation {
+            accept_header: true,
+            path_suffix: false,
+            query_param: None,
+        }
+    }
+}
+
+impl JsonNegotiation {
+    /// test if the request asks for JSON
+    pub fn wants_json(&self, req: &Request) -> bool {
+        (self.accept_header && req.headers.get::<Accept>().is_some_and(prefers_json))
+            || (self.path_suffix && has_json_suffix(&req.url.path()))
+            || self
+                .query_param
+                .as_ref()
+                .is_some_and(|p| has_json_flag(req.url.query(), p))
+    }
+}
+
+/// add `Accept` to the `Vary` header, unless it's already listed or the
+/// response varies on everything
+pub(crate) fn vary_on_accept(headers: &mut Headers) {
+    let listed = headers.get_raw("Vary").is_some_and(|lines| {
+        lines
+            .iter()
+            .flat_map(|line| {
+                String::from_utf8_lossy(line)
+                    .split(',')
+                    .map(|v| v.trim().to_ascii_lowercase())
+                    .collect::<Vec<_>>()
+            })
+            .any(|v| v == "*" || v == "accept")
+    });
+    if !listed {
+        headers.append_raw("Vary", b"Accept".to_vec());
+    }
+}
+
+fn quality_of<F>(accept: &Accept, f: F) -> u16
+where
+    F: Fn(&Mime) -> bool,
+{
+    accept
+        .iter()
+        .filter(|q: &&QualityItem<Mime>| f(&q.item))
+        .map(|q| q.quality.0)
+        .max()
+        .unwrap_or(0)
+}
+
+fn prefers_json(accept: &Accept) -> bool {
+    let json = quality_of(accept, |m| {
+        matches!(*m, Mime(TopLevel::Application, SubLevel::Json, _))
+    });
+    let html = quality_of(accept, |m| match *m {
+        Mime(TopLevel::Text, SubLevel::Html, _)
+        | Mime(TopLevel::Text, SubLevel::Star, _)
+        | Mime(TopLevel::Star, SubLevel::Star, _) => true,
+        Mime(TopLevel::Application, SubLevel::Ext(ref s), _) => s == "xhtml+xml",
+        _ => false,
+    });
+    json > html
+}
+
+fn has_json_suffix(path: &[&str]) -> bool {
+    path.last().is_some_and(|p| p.ends_with(".json"))
+}
+
+fn has_json_flag(query: Option<&str>, param: &str) -> bool {
+    query.is_some_and(|q| {
+        q.split('&').any(|pair| {
+            let mut kv = pair.splitn(2, '=');
+            kv.next() == Some(param) && kv.next() == Some("json")
+        })
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use iron::headers::{qitem, Accept, Headers, Quality, QualityItem, Vary};
+    use iron::mime::Mime;
+
+    fn accept(items: &[(&str, u16)]) -> Accept {
+        Accept(
+            items
+                .iter()
+                .map(|&(m, q)| QualityItem::new(m.parse::<Mime>().unwrap(), Quality(q)))
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn test_prefers_json() {
+        assert!(prefers_json(&Accept(vec![qitem(
+            "application/json".parse().unwrap()
+        )])));
+        assert!(prefers_json(&accept(&[
+            ("application/json", 1000),
+            ("*/*", 100)
+        ])));
+        assert!(!prefers_json(&accept(&[
+            ("text/html", 1000),
+            ("application/json", 900)
+        ])));
+        assert!(!prefers_json(&accept(&[("*/*", 1000)])));
+    }
+
+    #[test]
+    fn test_suffix_and_query() {
+        assert!(has_json_suffix(&["teams", "list.json"]));
+        assert!(!has_json_suffix(&["teams", "list"]));
+        assert!(has_json_flag(Some("page=2&format=json"), "format"));
+        assert!(!has_json_flag(Some("format=html"), "format"));
+        assert!(!has_json_flag(None, "format"));
+    }
+
+    #[test]
+    fn test_vary_on_accept() {
+        let vary = |headers: &Headers| headers.get::<Vary>().unwrap().to_string();
+
+        let mut headers = Headers::new();
+        vary_on_accept(&mut headers);
+        assert_eq!(vary(&headers), "Accept");
+
+        headers.set_raw("Vary", vec![b"Accept-Encoding".to_vec()]);
+        vary_on_accept(&mut headers);
+        assert_eq!(vary(&headers), "Accept-Encoding, Accept");
+
+        headers.set_raw("Vary", vec![b"Cookie, accept".to_vec()]);
+        vary_on_accept(&mut headers);
+        assert_eq!(vary(&headers), "Cookie, accept");
+
+        headers.set(Vary::Any);
+        vary_on_accept(&mut headers);
+        assert_eq!(vary(&headers), "*");
+    }
+}
@@ -0,0 +1,61 @@
+use middleware::HandlebarsEngine;
+
+use std::sync::{Arc, Condvar, Mutex, PoisonError};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+/// A running poller, returned by `Pollable::poll`
+///
+/// Dropping the handle leaves the poller running, call `stop` to end it.
+pub struct PollHandle {
+    stopped: Arc<(Mutex<bool>, Condvar)>,
+    thread: JoinHandle<()>,
+}
+
+impl PollHandle {
+    /// stop polling, a reload in progress is finished first
+    pub fn stop(&self) {
+        let (ref stopped, ref wakeup) = *self.stopped;
+        *stopped.lock().unwrap_or_else(PoisonError::into_inner) = true;
+        wakeup.notify_all();
+    }
+
+    /// wait for the poller thread to end, which happens only after `stop`
+    pub fn join(self) -> thread::Result<()> {
+        self.thread.join()
+    }
+}
+
+pub trait Pollable {
+    /// reload templates right away and then every `interval`, for sources
+    /// that can't be watched like `SqliteSource`
+    ///
+    /// Sources providing fingerprints only load changed templates, and
+    /// reloads are only logged when something changed.
+    fn poll(&self, interval: Duration) -> PollHandle;
+}
+
+impl Pollable for Arc<HandlebarsEngine> {
+    fn poll(&self, interval: Duration) -> PollHandle {
+        let hbs = self.clone();
+        let stopped = Arc::new((Mutex::new(false), Condvar::new()));
+        let poller = stopped.clone();
+        let thread = thread::spawn(move || loop {
+            match hbs.reload() {
+                Ok(ref report) if report.sources.iter().all(|s| s.loaded.is_empty()) => (),
+                Ok(report) => info!("Reloaded templates: {}", report),
+                Err(report) => error!("Failed to reload templates: {}", report),
+            }
+
+            let (ref stopped, ref wakeup) = *poller;
+            let stopped = stopped.lock().unwrap_or_else(PoisonError::into_inner);
+            let (stopped, _) = wakeup
+                .wait_timeout_while(stopped, interval, |stopped| !*stopped)
+                .unwrap_or_else(PoisonError::into_inner);
+            if *stopped {
+                break;
+            }
+        });
+        PollHandle { stopped, thread }
+    }
+}
@@ -0,0 +1,440 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::error::Error;
+use std::fmt;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+use handlebars::{Handlebars, Template, TemplateError};
+use source::{Fingerprint, Source, SourceError};
+
+/// Templates loaded from a source on the last reload
+#[derive(Clone)]
+pub(crate) struct SourceState {
+    fingerprints: Option<HashMap<String, Fingerprint>>,
+    pub templates: HashMap<String, Template>,
+}
+
+/// What to do when several sources provide a template of the same name
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum CollisionPolicy {
+    /// fail the reload, the template is reported as failed for every
+    /// source but the first one providing it
+    Error,
+    /// log a warning and use the template of the source added last
+    #[default]
+    WarnOverride,
+    /// log a warning and use the template of the source added first
+    KeepFirst,
+}
+
+/// A template name provided by more than one source
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Collision {
+    pub name: String,
+    /// indices of sources providing the template, in the order they were
+    /// added to the engine
+    pub sources: Vec<usize>,
+    /// index of the source whose template is used, `None` when the
+    /// collision failed the reload
+    pub used: Option<usize>,
+}
+
+impl fmt::Display for Collision {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        write!(
+            f,
+            "template {} is provided by sources {:?}",
+            self.name, self.sources
+        )?;
+        if let Some(used) = self.used {
+            write!(f, ", using source {}", used)?;
+        }
+        Ok(())
+    }
+}
+
+impl Error for Collision {}
+
+/// Source providing each template, as of the last successful reload
+#[derive(Default)]
+pub(crate) struct Ownership {
+    pub owners: HashMap<String, usize>,
+    pub collisions: Vec<Collision>,
+}
+
+/// A template that failed to load
+#[derive(Debug)]
+pub struct TemplateFailure {
+    /// template name, when known
+    pub name: Option<String>,
+    /// file the template was loaded from, see `Source::template_path`
+    pub path: Option<PathBuf>,
+    pub line: Option<usize>,
+    pub column: Option<usize>,
+    pub cause: SourceError,
+}
+
+impl TemplateFailure {
+    fn new(source: &dyn Source, name: Option<&str>, cause: SourceError) -> TemplateFailure {
+        let template_error = cause.cause.downcast_ref::<TemplateError>();
+        let name = name
+            .map(|n| n.to_owned())
+            .or_else(|| template_error.and_then(|e| e.template_name.clone()));
+        TemplateFailure {
+            path: name.as_ref().and_then(|n| source.template_path(n)),
+            line: template_error.and_then(|e| e.line_no),
+            column: template_error.and_then(|e| e.column_no),
+            name,
+            cause,
+        }
+    }
+}
+
+impl fmt::Display for TemplateFailure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match (&self.path, &self.name) {
+            (Some(path), _) => write!(f, "{}", path.display())?,
+            (None, Some(name)) => write!(f, "{}", name)?,
+            (None, None) => write!(f, "<source>")?,
+        }
+        if let Some(line) = self.line {
+            write!(f, ":{}", line)?;
+            if let Some(column) = self.column {
+                write!(f, ":{}", column)?;
+            }
+        }
+        write!(f, ": {}", self.cause)
+    }
+}
+
+/// Outcome of reloading a single source
+#[derive(Debug, Default)]
+pub struct SourceReport {
+    /// templates parsed on this reload
+    pub loaded: Vec<String>,
+    /// unchanged templates, reused from the previous reload
+    pub skipped: Vec<String>,
+    pub failed: Vec<TemplateFailure>,
+    pub elapsed: Duration,
+}
+
+/// Outcome of `HandlebarsEngine::reload`, listing templates of every source
+///
+/// Sources are reported in the order they were added. When loading stops
+/// at the first failure, sources after it are not reported.
+#[derive(Debug, Default)]
+pub struct ReloadReport {
+    pub sources: Vec<SourceReport>,
+    /// templates provided by several sources, see `CollisionPolicy`
+    pub collisions: Vec<Collision>,
+    pub elapsed: Duration,
+}
+
+impl ReloadReport {
+    /// whether all templates were loaded
+    pub fn is_ok(&self) -> bool {
+        self.sources.iter().all(|s| s.failed.is_empty())
+    }
+
+    /// all failed templates, of all sources
+    pub fn failures(&self) -> impl Iterator<Item = &TemplateFailure> {
+        self.sources.iter().flat_map(|s| s.failed.iter())
+    }
+
+    fn count<F>(&self, f: F) -> usize
+    where
+        F: Fn(&SourceReport) -> usize,
+    {
+        self.sources.iter().map(f).sum()
+    }
+}
+
+impl fmt::Display for ReloadReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        write!(
+            f,
+            "{} templates loaded, {} unchanged, {} failed in {:?}",
+            self.count(|s| s.loaded.len()),
+            self.count(|s| s.skipped.len()),
+            self.count(|s| s.failed.len()),
+            self.elapsed
+        )?;
+        for failure in self.failures() {
+            write!(f, "\n  {}", failure)?;
+        }
+        Ok(())
+    }
+}
+
+impl Error for ReloadReport {}
+
+/// Notification of a reload, see `HandlebarsEngine::on_reload`
+#[derive(Debug)]
+pub struct ReloadEvent {
+    /// generation of the registry after this reload, see
+    /// `HandlebarsEngine::generation`
+    pub generation: u64,
+    pub result: Result<ReloadReport, ReloadReport>,
+    /// templates added, removed or changed by this reload, empty when it
+    /// failed
+    pub changed: BTreeSet<String>,
+}
+
+/// names of templates that differ between two registries
+pub(crate) fn changed_templates(
+    before: &Handlebars<'static>,
+    after: &Handlebars<'static>,
+) -> BTreeSet<String> {
+    let (before, after) = (before.get_templates(), after.get_templates());
+    let removed = before.keys().filter(|name| !after.contains_key(*name));
+    let changed = after
+        .iter()
+        .filter(|&(name, tpl)| before.get(name) != Some(tpl))
+        .map(|(name, _)| name);
+    removed.chain(changed).cloned().collect()
+}
+
+/// load a source, reusing templates of its previous state whose fingerprint
+/// didn't change
+///
+/// `empty` is a registry without templates, whose settings are used to
+/// parse templates. Changed templates of sources with fingerprints are
+/// loaded with one `Source::load_templates` call, with `keep_going` every
+/// broken template is reported, otherwise only the first one. Sources
+/// without fingerprints report their first failure only.
+pub(crate) fn load_source(
+    source: &dyn Source,
+    previous: Option<&SourceState>,
+    empty: &Handlebars<'static>,
+    keep_going: bool,
+) -> (Option<SourceState>, SourceReport) {
+    let start = Instant::now();
+    let mut report = SourceReport::default();
+    let state = load_into(source, previous, empty, keep_going, &mut report);
+    report.loaded.sort();
+    report.skipped.sort();
+    report.elapsed = start.elapsed();
+    (state, report)
+}
+
+/// keep templates of a source from its previous state, without loading it
+pub(crate) fn reuse(previous: &SourceState) -> (Option<SourceState>, SourceReport) {
+    let mut skipped: Vec<String> = previous.templates.keys().cloned().collect();
+    skipped.sort();
+    let report = SourceReport {
+        skipped,
+        ..SourceReport::default()
+    };
+    (Some(previous.clone()), report)
+}
+
+fn load_into(
+    source: &dyn Source,
+    previous: Option<&SourceState>,
+    empty: &Handlebars<'static>,
+    keep_going: bool,
+    report: &mut SourceReport,
+) -> Option<SourceState> {
+    let fingerprints = match source.fingerprints() {
+        Ok(fingerprints) => fingerprints,
+        Err(e) => {
+            report.failed.push(TemplateFailure::new(source, None, e));
+            return None;
+        }
+    };
+    let mut scratch = empty.clone();
+    let mut templates = HashMap::new();
+
+    match fingerprints {
+        Some(ref current) => {
+            let previous = previous.and_then(|p| p.fingerprints.as_ref().map(|f| (p, f)));
+            let mut changed = Vec::new();
+            for (name, fingerprint) in current {
+                let unchanged = previous.and_then(|(previous, previous_fingerprints)| {
+                    previous
+                        .templates
+                        .get(name)
+                        .filter(|_| previous_fingerprints.get(name) == Some(fingerprint))
+                });
+                if let Some(tpl) = unchanged {
+                    templates.insert(name.clone(), tpl.clone());
+                    report.skipped.push(name.clone());
+                    continue;
+                }
+                debug!("Loading changed template {}", name);
+                changed.push(name.clone());
+            }
+            if !changed.is_empty() {
+                changed.sort();
+                let failed = source.load_templates(&changed, &mut scratch);
+                let reported = if keep_going { failed.len() } else { 1 };
+                for (name, e) in failed.into_iter().take(reported) {
+                    report
+                        .failed
+                        .push(TemplateFailure::new(source, name.as_deref(), e));
+                }
+                if !keep_going && !report.failed.is_empty() {
+                    return None;
+                }
+            }
+        }
+        None => {
+            if let Err(e) = source.load(&mut scratch) {
+                report.failed.push(TemplateFailure::new(source, None, e));
+                return None;
+            }
+        }
+    }
+
+    for (name, tpl) in scratch.get_templates() {
+        templates.insert(name.clone(), tpl.clone());
+        report.loaded.push(name.clone());
+    }
+    if !report.failed.is_empty() {
+        return None;
+    }
+    Some(SourceState {
+        fingerprints,
+        templates,
+    })
+}
+
+/// find out which source provides each template, according to `policy`
+///
+/// Collisions are added to `report`. Under `CollisionPolicy::Error` they
+/// are also reported as failed templates of the shadowing sources.
+pub(crate) fn resolve<S>(
+    sources: &[S],
+    states: &[Option<SourceState>],
+    policy: CollisionPolicy,
+    report: &mut ReloadReport,
+) -> Ownership
+where
+    S: AsRef<dyn Source + Send + Sync>,
+{
+    let mut providers: BTreeMap<&str, Vec<usize>> = BTreeMap::new();
+    for (i, state) in states.iter().enumerate() {
+        for name in state.iter().flat_map(|s| s.templates.keys()) {
+            providers.entry(name).or_default().push(i);
+        }
+    }
+
+    let mut ownership = Ownership::default();
+    for (name, indices) in providers {
+        if indices.len() > 1 {
+            let used = match policy {
+                CollisionPolicy::Error => None,
+                CollisionPolicy::WarnOverride => indices.last().cloned(),
+                CollisionPolicy::KeepFirst => indices.first().cloned(),
+            };
+            let collision = Collision {
+                name: name.to_owned(),
+                sources: indices.clone(),
+                used,
+            };
+            if used.is_some() {
+                warn!("{}", collision);
+            } else {
+                for &i in &indices[1..] {
+                    let cause = SourceError {
+                        cause: Box::new(collision.clone()),
+                    };
+                    let source = sources[i].as_ref();
+                    report.sources[i]
+                        .failed
+                        .push(TemplateFailure::new(source, Some(name), cause));
+                }
+            }
+            ownership.collisions.push(collision);
+        }
+        let owner = match policy {
+            CollisionPolicy::KeepFirst => indices[0],
+            _ => indices[indices.len() - 1],
+        };
+        ownership.owners.insert(name.to_owned(), owner);
+    }
+    report.collisions = ownership.collisions.clone();
+    ownership
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use sources::memory::MemorySource;
+    use std::cell::Cell;
+    use std::collections::BTreeMap;
+
+    #[test]
+    fn test_keep_going() {
+        let mut templates = BTreeMap::new();
+        templates.insert("a".to_owned(), "{{#if}}".to_owned());
+        templates.insert("b".to_owned(), "fine".to_owned());
+        templates.insert("c".to_owned(), "{{/each}}".to_owned());
+        let source = MemorySource(templates);
+        let empty = Handlebars::new();
+
+        let (state, report) = load_source(&source, None, &empty, true);
+        assert!(state.is_none());
+        assert_eq!(report.loaded, vec!["b".to_owned()]);
+        let mut failed: Vec<_> = report
+            .failed
+            .iter()
+            .map(|f| f.name.clone().unwrap())
+            .collect();
+        failed.sort();
+        assert_eq!(failed, vec!["a".to_owned(), "c".to_owned()]);
+        assert_eq!(report.failed[0].line, Some(1));
+
+        let (_, report) = load_source(&source, None, &empty, false);
+        assert_eq!(report.failed.len(), 1);
+    }
+
+    /// a source with fingerprints relying on the default `load_templates`
+    struct WholeSource {
+        templates: BTreeMap<String, String>,
+        loads: Cell<usize>,
+    }
+
+    impl Source for WholeSource {
+        fn load(&self, reg: &mut Handlebars) -> Result<(), SourceError> {
+            self.loads.set(self.loads.get() + 1);
+            for (name, tpl) in self.templates.iter() {
+                reg.register_template_string(name, tpl)?;
+            }
+            Ok(())
+        }
+
+        fn fingerprints(&self) -> Result<Option<HashMap<String, Fingerprint>>, SourceError> {
+            Ok(Some(
+                self.templates
+                    .iter()
+                    .map(|(name, tpl)| (name.clone(), Fingerprint::of_content(tpl)))
+                    .collect(),
+            ))
+        }
+    }
+
+    #[test]
+    fn test_load_once_per_source() {
+        let mut source = WholeSource {
+            templates: BTreeMap::new(),
+            loads: Cell::new(0),
+        };
+        for name in &["a", "b", "c"] {
+            source.templates.insert(name.to_string(), name.to_string());
+        }
+        let empty = Handlebars::new();
+
+        let (state, report) = load_source(&source, None, &empty, false);
+        assert_eq!(source.loads.get(), 1);
+        assert_eq!(report.loaded.len(), 3);
+
+        source
+            .templates
+            .insert("b".to_owned(), "changed".to_owned());
+        let (_, report) = load_source(&source, state.as_ref(), &empty, false);
+        assert_eq!(source.loads.get(), 2);
+        assert_eq!(report.loaded, vec!["b".to_owned()]);
+        assert_eq!(report.skipped.len(), 2);
+    }
+}
@@ -0,0 +1,61 @@
+use middleware::HandlebarsEngine;
+
+use signal_hook::consts::SIGHUP;
+use signal_hook::iterator::{Handle, Signals};
+use std::io;
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+
+/// An installed `SIGHUP` handler, returned by
+/// `SighupReloadable::reload_on_sighup`
+///
+/// Dropping the handle leaves the handler running, call `stop` to end it.
+pub struct SighupHandle {
+    signals: Handle,
+    thread: JoinHandle<()>,
+}
+
+impl SighupHandle {
+    /// stop reloading on `SIGHUP`, a reload in progress is finished first
+    pub fn stop(&self) {
+        self.signals.close();
+    }
+
+    /// wait for the handler thread to end, which happens only after `stop`
+    pub fn join(self) -> thread::Result<()> {
+        self.thread.join()
+    }
+}
+
+pub trait SighupReloadable {
+    /// reload templates whenever the process receives `SIGHUP`, with
+    /// feature `sighup` on Unix
+    ///
+    /// Reloads run on a background thread and are logged. A failed reload
+    /// keeps the current templates, like `HandlebarsEngine::reload`. Once
+    /// installed, `SIGHUP` no longer terminates the process, even after the
+    /// handle is stopped. Installing it again for the same engine reloads
+    /// it twice per signal.
+    fn reload_on_sighup(&self) -> io::Result<SighupHandle>;
+}
+
+impl SighupReloadable for Arc<HandlebarsEngine> {
+    fn reload_on_sighup(&self) -> io::Result<SighupHandle> {
+        let mut signals = Signals::new([SIGHUP])?;
+        let handle = signals.handle();
+        let hbs = self.clone();
+        let thread = thread::spawn(move || {
+            for _ in signals.forever() {
+                info!("Received SIGHUP, reloading templates");
+                match hbs.reload() {
+                    Ok(report) => info!("Reloaded templates: {}", report),
+                    Err(report) => error!("Failed to reload templates: {}", report),
+                }
+            }
+        });
+        Ok(SighupHandle {
+            signals: handle,
+            thread,
+        })
+    }
+}
@@ -1,6 +1,13 @@
-use handlebars::{Handlebars, TemplateError, TemplateFileError};
+use handlebars::{Handlebars, TemplateError};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
 use std::error::Error;
 use std::fmt;
+use std::fs::Metadata;
+use std::hash::{Hash, Hasher};
+use std::io;
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime};
 
 #[derive(Debug)]
 pub struct SourceError {
@@ -19,22 +26,164 @@ impl Error for SourceError {
     }
 }
 
-impl From<TemplateError> for SourceError {
-    fn from(err: TemplateError) -> SourceError {
+impl From<io::Error> for SourceError {
+    fn from(err: io::Error) -> SourceError {
         SourceError {
             cause: Box::new(err),
         }
     }
 }
 
-impl From<TemplateFileError> for SourceError {
-    fn from(err: TemplateFileError) -> SourceError {
+impl From<TemplateError> for SourceError {
+    fn from(err: TemplateError) -> SourceError {
         SourceError {
             cause: Box::new(err),
         }
     }
 }
 
+/// State of a template in its source, used to find out changed templates
+/// on reload
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Fingerprint {
+    modified: Option<SystemTime>,
+    size: u64,
+    hash: Option<u64>,
+}
+
+impl Fingerprint {
+    /// fingerprint of a template file, by modification time and size
+    pub fn of_file(metadata: &Metadata) -> Fingerprint {
+        Fingerprint {
+            modified: metadata.modified().ok(),
+            size: metadata.len(),
+            hash: None,
+        }
+    }
+
+    /// fingerprint of template text, by its hash
+    pub fn of_content(content: &str) -> Fingerprint {
+        let mut hasher = DefaultHasher::new();
+        content.hash(&mut hasher);
+        Fingerprint {
+            modified: None,
+            size: content.len() as u64,
+            hash: Some(hasher.finish()),
+        }
+    }
+
+    /// fingerprint of a template made of several parts, which changes
+    /// when any of them does
+    pub fn combine(parts: &[Fingerprint]) -> Fingerprint {
+        let mut hasher = DefaultHasher::new();
+        parts.hash(&mut hasher);
+        Fingerprint {
+            modified: None,
+            size: parts.iter().map(|p| p.size).sum(),
+            hash: Some(hasher.finish()),
+        }
+    }
+}
+
+/// What to watch for changes of a source, see `Source::watch_targets`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum WatchTarget {
+    /// a directory, watched recursively
+    Directory(PathBuf),
+    /// a single file, also noticed when replaced by a rename
+    File(PathBuf),
+    /// no files to watch, reload the source periodically
+    Poll(Duration),
+}
+
 pub trait Source {
     fn load(&self, reg: &mut Handlebars) -> Result<(), SourceError>;
+
+    /// fingerprints of all templates in this source, by template name
+    ///
+    /// Sources returning `Some` are reloaded incrementally: only templates
+    /// whose fingerprint changed are loaded again, with `load_templates`.
+    /// By default `None` is returned, and the whole source is loaded on
+    /// every reload.
+    fn fingerprints(&self) -> Result<Option<HashMap<String, Fingerprint>>, SourceError> {
+        Ok(None)
+    }
+
+    /// load a single template of this source
+    ///
+    /// The default implementation loads the whole source and keeps only
+    /// the requested template. Sources returning fingerprints should
+    /// provide a cheaper way.
+    fn load_template(&self, name: &str, reg: &mut Handlebars) -> Result<(), SourceError> {
+        let mut all = Handlebars::new();
+        self.load(&mut all)?;
+        if let Some(tpl) = all.get_template(name) {
+            reg.register_template(name, tpl.clone());
+        }
+        Ok(())
+    }
+
+    /// load templates `names` of this source, used on reload for templates
+    /// whose fingerprint changed
+    ///
+    /// Failures are returned with the name of their template, when known.
+    /// The default implementation loads the whole source once and keeps
+    /// the requested templates. Sources overriding `load_template` should
+    /// override this too, calling `load_template` for each name.
+    fn load_templates(
+        &self,
+        names: &[String],
+        reg: &mut Handlebars,
+    ) -> Vec<(Option<String>, SourceError)> {
+        let mut all = Handlebars::new();
+        if let Err(e) = self.load(&mut all) {
+            return vec![(None, e)];
+        }
+        for name in names {
+            if let Some(tpl) = all.get_template(name) {
+                reg.register_template(name, tpl.clone());
+            }
+        }
+        Vec::new()
+    }
+
+    /// text of a template loaded by this source, used to show template
+    /// excerpts on the developer error page
+    fn source_text(&self, _name: &str) -> Option<String> {
+        None
+    }
+
+    /// file a template was loaded from, reported when it fails to load
+    fn template_path(&self, _name: &str) -> Option<PathBuf> {
+        None
+    }
+
+    /// what to watch to reload this source on changes, used by
+    /// `Watchable::watch_sources`
+    ///
+    /// By default the source isn't watched.
+    fn watch_targets(&self) -> Vec<WatchTarget> {
+        Vec::new()
+    }
+}
+
+/// load `names` one by one with `Source::load_template`, for sources
+/// overriding it
+pub(crate) fn load_each<S>(
+    source: &S,
+    names: &[String],
+    reg: &mut Handlebars,
+) -> Vec<(Option<String>, SourceError)>
+where
+    S: Source + ?Sized,
+{
+    names
+        .iter()
+        .filter_map(|name| {
+            source
+                .load_template(name, reg)
+                .err()
+                .map(|e| (Some(name.clone()), e))
+        })
+        .collect()
 }
@@ -0,0 +1,224 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::PathBuf;
+use std::sync::{Mutex, MutexGuard, PoisonError};
+
+use flate2::read::GzDecoder;
+use handlebars::Handlebars;
+use source::{self, Fingerprint, Source, SourceError, WatchTarget};
+use zip::ZipArchive;
+
+/// A source loading templates from a `.tar`, `.tar.gz`, `.tgz` or `.zip`
+/// archive, with feature `archive`
+///
+/// Files under directory `prefix` in the archive whose name ends with
+/// `suffix` are loaded, named like `DirectorySource` names them. The
+/// archive is read again on every reload; to pick up a new bundle, replace
+/// the archive file atomically and reload, or watch it with
+/// `Watchable::watch_file`. Templates have no file of their own, so their
+/// failures are reported by template name.
+pub struct ArchiveSource {
+    pub path: PathBuf,
+    pub prefix: String,
+    pub suffix: &'static str,
+    /// template text by name, as of the last `fingerprints`
+    entries: Mutex<Option<BTreeMap<String, String>>>,
+}
+
+impl ArchiveSource {
+    pub fn new<P>(path: P, prefix: &str, suffix: &'static str) -> ArchiveSource
+    where
+        P: Into<PathBuf>,
+    {
+        // match whole directory names, `bundle` shouldn't match `bundles/`
+        let prefix = prefix.trim_start_matches("./").trim_matches('/');
+        ArchiveSource {
+            path: path.into(),
+            prefix: if prefix.is_empty() {
+                String::new()
+            } else {
+                format!("{}/", prefix)
+            },
+            suffix,
+            entries: Mutex::new(None),
+        }
+    }
+
+    /// templates read by the last `fingerprints`, which replaces them at
+    /// once, so a poisoned lock still holds a whole archive
+    fn lock_entries(&self) -> MutexGuard<'_, Option<BTreeMap<String, String>>> {
+        self.entries.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// template name of archive entry `path`, if it's a template
+    fn template_name(&self, path: &str) -> Option<String> {
+        let path = path.trim_start_matches("./");
+        let name = path
+            .strip_prefix(self.prefix.as_str())?
+            .trim_start_matches('/');
+        let stem = name.rsplit('/').next()?;
+        if stem.starts_with('.') || stem.starts_with('#') {
+            return None;
+        }
+        name.strip_suffix(self.suffix)
+            .filter(|n| !n.is_empty())
+            .map(|n| n.to_owned())
+    }
+
+    /// read all templates of the archive
+    fn read(&self) -> Result<BTreeMap<String, String>, SourceError> {
+        let file = File::open(&self.path)?;
+        let path = self.path.to_string_lossy();
+        // other files, like images next to the templates, aren't read at
+        // all since they may not be text
+        let mut entries = BTreeMap::new();
+
+        if path.ends_with(".zip") {
+            let mut archive = ZipArchive::new(file).map_err(io::Error::other)?;
+            for i in 0..archive.len() {
+                let mut file = archive.by_index(i).map_err(io::Error::other)?;
+                if !file.is_file() {
+                    continue;
+                }
+                if let Some(name) = self.template_name(file.name()) {
+                    let mut content = String::new();
+                    file.read_to_string(&mut content)?;
+                    entries.insert(name, content);
+                }
+            }
+        } else {
+            let reader: Box<dyn Read> = if path.ends_with(".gz") || path.ends_with(".tgz") {
+                Box::new(GzDecoder::new(file))
+            } else {
+                Box::new(file)
+            };
+            let mut archive = tar::Archive::new(reader);
+            for file in archive.entries()? {
+                let mut file = file?;
+                if !file.header().entry_type().is_file() {
+                    continue;
+                }
+                let path = file.path()?.to_string_lossy().replace('\\', "/");
+                if let Some(name) = self.template_name(&path) {
+                    let mut content = String::new();
+                    file.read_to_string(&mut content)?;
+                    entries.insert(name, content);
+                }
+            }
+        }
+        Ok(entries)
+    }
+
+    fn entry(&self, name: &str) -> Result<Option<String>, SourceError> {
+        if let Some(ref entries) = *self.lock_entries() {
+            return Ok(entries.get(name).cloned());
+        }
+        Ok(self.read()?.remove(name))
+    }
+}
+
+impl Source for ArchiveSource {
+    fn load(&self, reg: &mut Handlebars) -> Result<(), SourceError> {
+        for (name, content) in self.read()? {
+            reg.register_template_string(&name, content)?;
+        }
+        Ok(())
+    }
+
+    fn fingerprints(&self) -> Result<Option<HashMap<String, Fingerprint>>, SourceError> {
+        let entries = self.read()?;
+        let fingerprints = entries
+            .iter()
+            .map(|(name, content)| (name.clone(), Fingerprint::of_content(content)))
+            .collect();
+        *self.lock_entries() = Some(entries);
+        Ok(Some(fingerprints))
+    }
+
+    fn load_template(&self, name: &str, reg: &mut Handlebars) -> Result<(), SourceError> {
+        if let Some(content) = self.entry(name)? {
+            reg.register_template_string(name, content)?;
+        }
+        Ok(())
+    }
+
+    fn load_templates(
+        &self,
+        names: &[String],
+        reg: &mut Handlebars,
+    ) -> Vec<(Option<String>, SourceError)> {
+        source::load_each(self, names, reg)
+    }
+
+    fn source_text(&self, name: &str) -> Option<String> {
+        self.entry(name).ok()?
+    }
+
+    fn watch_targets(&self) -> Vec<WatchTarget> {
+        vec![WatchTarget::File(self.path.clone())]
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+    use std::env;
+    use std::fs;
+    use std::io::Write;
+    use std::path::Path;
+    use std::process;
+    use zip::write::{FileOptions, ZipWriter};
+
+    const FILES: &[(&str, &[u8])] = &[
+        ("bundle/index.hbs", b"index"),
+        ("bundle/some/page.hbs", b"page"),
+        ("bundle/.hidden.hbs", b"hidden"),
+        ("bundle/readme.txt", b"readme"),
+        ("bundle/logo.png", b"\x89PNG\r\n\x1a\n\xff\xfe"),
+        ("other/index.hbs", b"other"),
+        ("bundles/index.hbs", b"bundles"),
+    ];
+
+    fn check(path: &Path) {
+        let source = ArchiveSource::new(path, "./bundle", ".hbs");
+        let mut reg = Handlebars::new();
+        source.load(&mut reg).unwrap();
+        fs::remove_file(path).unwrap();
+
+        let mut names: Vec<_> = reg.get_templates().keys().cloned().collect();
+        names.sort();
+        assert_eq!(names, vec!["index".to_owned(), "some/page".to_owned()]);
+        assert_eq!(reg.render("some/page", &()).unwrap(), "page");
+    }
+
+    #[test]
+    fn test_tar_gz() {
+        let path = env::temp_dir().join(format!("hbsi-{}.tar.gz", process::id()));
+        let encoder = GzEncoder::new(File::create(&path).unwrap(), Compression::default());
+        let mut builder = tar::Builder::new(encoder);
+        for &(name, content) in FILES {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(content.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            builder.append_data(&mut header, name, content).unwrap();
+        }
+        builder.into_inner().unwrap().finish().unwrap();
+        check(&path);
+    }
+
+    #[test]
+    fn test_zip() {
+        let path = env::temp_dir().join(format!("hbsi-{}.zip", process::id()));
+        let mut zip = ZipWriter::new(File::create(&path).unwrap());
+        for &(name, content) in FILES {
+            zip.start_file(name, FileOptions::default()).unwrap();
+            zip.write_all(content).unwrap();
+        }
+        zip.finish().unwrap();
+        check(&path);
+    }
+}
@@ -1,6 +1,9 @@
+use std::collections::HashMap;
+use std::fs;
 use std::path::PathBuf;
 
-use source::{Source, SourceError};
+use source::{self, Fingerprint, Source, SourceError, WatchTarget};
+use walkdir::WalkDir;
 
 use handlebars::Handlebars;
 
@@ -16,14 +19,83 @@ impl DirectorySource {
     {
         DirectorySource {
             prefix: prefix.into(),
-            suffix: suffix,
+            suffix,
         }
     }
+
+    /// template names and paths in this directory
+    ///
+    /// Hidden files and temporary files, starting with `#`, are ignored.
+    /// The name of a template is its path relative to `prefix`, without
+    /// `suffix` and with `/` as separator.
+    pub fn templates(&self) -> Vec<(String, PathBuf)> {
+        WalkDir::new(&self.prefix)
+            .min_depth(1)
+            .into_iter()
+            .filter_map(|e| e.ok().map(|e| e.into_path()))
+            .filter(|path| path.to_string_lossy().ends_with(self.suffix))
+            .filter(|path| {
+                path.file_stem()
+                    .map(|stem| stem.to_string_lossy())
+                    .is_some_and(|stem| !(stem.starts_with('.') || stem.starts_with('#')))
+            })
+            .filter_map(|path| {
+                let name = path
+                    .strip_prefix(&self.prefix)
+                    .ok()?
+                    .components()
+                    .map(|c| c.as_os_str().to_string_lossy())
+                    .collect::<Vec<_>>()
+                    .join("/");
+                let name = name
+                    .strip_suffix(self.suffix)
+                    .map(|s| s.to_owned())
+                    .unwrap_or(name);
+                Some((name, path))
+            })
+            .collect()
+    }
 }
 
 impl Source for DirectorySource {
     fn load(&self, reg: &mut Handlebars) -> Result<(), SourceError> {
-        reg.register_templates_directory(self.suffix, &self.prefix)
+        for (name, path) in self.templates() {
+            reg.register_template_file(&name, &path)?;
+        }
+        Ok(())
+    }
+
+    fn source_text(&self, name: &str) -> Option<String> {
+        fs::read_to_string(self.template_path(name)?).ok()
+    }
+
+    fn template_path(&self, name: &str) -> Option<PathBuf> {
+        Some(self.prefix.join(format!("{}{}", name, self.suffix)))
+    }
+
+    fn watch_targets(&self) -> Vec<WatchTarget> {
+        vec![WatchTarget::Directory(self.prefix.clone())]
+    }
+
+    fn fingerprints(&self) -> Result<Option<HashMap<String, Fingerprint>>, SourceError> {
+        let mut fingerprints = HashMap::new();
+        for (name, path) in self.templates() {
+            fingerprints.insert(name, Fingerprint::of_file(&fs::metadata(path)?));
+        }
+        Ok(Some(fingerprints))
+    }
+
+    fn load_template(&self, name: &str, reg: &mut Handlebars) -> Result<(), SourceError> {
+        let path = self.prefix.join(format!("{}{}", name, self.suffix));
+        reg.register_template_file(name, path)
             .map_err(SourceError::from)
     }
+
+    fn load_templates(
+        &self,
+        names: &[String],
+        reg: &mut Handlebars,
+    ) -> Vec<(Option<String>, SourceError)> {
+        source::load_each(self, names, reg)
+    }
 }
@@ -0,0 +1,157 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+use handlebars::Handlebars;
+use source::{self, Fingerprint, Source, SourceError, WatchTarget};
+use sources::directory::DirectorySource;
+
+/// A source of templates compiled into the binary
+///
+/// Usually created with `embed_templates!`, which requires feature
+/// `embed`:
+///
+/// ```ignore
+/// let source = embed_templates!("templates", ".hbs")
+///     .read_from_disk(cfg!(debug_assertions));
+/// ```
+///
+/// Templates are named like `DirectorySource` names them. With
+/// `read_from_disk`, templates are loaded from the directory they were
+/// embedded from instead, so they can be edited and reloaded during
+/// development. Note that templates added to the directory are only
+/// embedded when the crate using the macro gets rebuilt.
+pub struct EmbeddedSource {
+    templates: &'static [(&'static str, &'static str)],
+    origin: Option<DirectorySource>,
+    from_disk: bool,
+}
+
+impl EmbeddedSource {
+    pub fn new(templates: &'static [(&'static str, &'static str)]) -> EmbeddedSource {
+        EmbeddedSource {
+            templates,
+            origin: None,
+            from_disk: false,
+        }
+    }
+
+    /// set the directory templates were embedded from
+    pub fn origin<P>(mut self, prefix: P, suffix: &'static str) -> EmbeddedSource
+    where
+        P: Into<PathBuf>,
+    {
+        self.origin = Some(DirectorySource::new(prefix, suffix));
+        self
+    }
+
+    /// load templates from the directory set by `origin` instead of the
+    /// embedded ones
+    pub fn read_from_disk(mut self, enable: bool) -> EmbeddedSource {
+        self.from_disk = enable;
+        self
+    }
+
+    fn disk(&self) -> Option<&DirectorySource> {
+        self.origin.as_ref().filter(|_| self.from_disk)
+    }
+
+    fn embedded(&self, name: &str) -> Option<&'static str> {
+        self.templates
+            .iter()
+            .find(|&&(n, _)| n == name)
+            .map(|&(_, tpl)| tpl)
+    }
+}
+
+impl Source for EmbeddedSource {
+    fn load(&self, reg: &mut Handlebars) -> Result<(), SourceError> {
+        if let Some(disk) = self.disk() {
+            return disk.load(reg);
+        }
+        for &(name, tpl) in self.templates {
+            reg.register_template_string(name, tpl)?;
+        }
+        Ok(())
+    }
+
+    fn fingerprints(&self) -> Result<Option<HashMap<String, Fingerprint>>, SourceError> {
+        if let Some(disk) = self.disk() {
+            return disk.fingerprints();
+        }
+        Ok(Some(
+            self.templates
+                .iter()
+                .map(|&(name, tpl)| (name.to_owned(), Fingerprint::of_content(tpl)))
+                .collect(),
+        ))
+    }
+
+    fn load_template(&self, name: &str, reg: &mut Handlebars) -> Result<(), SourceError> {
+        if let Some(disk) = self.disk() {
+            return disk.load_template(name, reg);
+        }
+        if let Some(tpl) = self.embedded(name) {
+            reg.register_template_string(name, tpl)?;
+        }
+        Ok(())
+    }
+
+    fn load_templates(
+        &self,
+        names: &[String],
+        reg: &mut Handlebars,
+    ) -> Vec<(Option<String>, SourceError)> {
+        source::load_each(self, names, reg)
+    }
+
+    fn source_text(&self, name: &str) -> Option<String> {
+        match self.disk() {
+            Some(disk) => disk.source_text(name),
+            None => self.embedded(name).map(|tpl| tpl.to_owned()),
+        }
+    }
+
+    fn template_path(&self, name: &str) -> Option<PathBuf> {
+        self.origin.as_ref()?.template_path(name)
+    }
+
+    fn watch_targets(&self) -> Vec<WatchTarget> {
+        match self.disk() {
+            Some(disk) => disk.watch_targets(),
+            None => Vec::new(),
+        }
+    }
+}
+
+/// embed templates of a directory into the binary, creating an
+/// `EmbeddedSource`
+///
+/// The directory is relative to the manifest of the crate using the
+/// macro, templates are those with the given suffix.
+#[cfg(feature = "embed")]
+#[macro_export]
+macro_rules! embed_templates {
+    ($dir:expr, $suffix:expr) => {
+        $crate::EmbeddedSource::new($crate::__embedded_templates!($dir, $suffix))
+            .origin(concat!(env!("CARGO_MANIFEST_DIR"), "/", $dir), $suffix)
+    };
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_embedded_source() {
+        let source = EmbeddedSource::new(&[("index", "{{name}}"), ("some/page", "page")])
+            .origin("./examples/templates/", ".hbs");
+        let mut reg = Handlebars::new();
+        source.load(&mut reg).unwrap();
+        assert_eq!(reg.render("index", &json!({"name": "x"})).unwrap(), "x");
+        assert!(reg.get_template("some/path/hello").is_none());
+
+        let mut reg = Handlebars::new();
+        source.read_from_disk(true).load(&mut reg).unwrap();
+        assert!(reg.get_template("some/path/hello").is_some());
+    }
+}
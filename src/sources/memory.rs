@@ -1,6 +1,6 @@
 use handlebars::Handlebars;
-use source::{Source, SourceError};
-use std::collections::BTreeMap;
+use source::{self, Fingerprint, Source, SourceError};
+use std::collections::{BTreeMap, HashMap};
 
 pub struct MemorySource(pub BTreeMap<String, String>);
 
@@ -11,4 +11,32 @@ impl Source for MemorySource {
         }
         Ok(())
     }
+
+    fn source_text(&self, name: &str) -> Option<String> {
+        self.0.get(name).cloned()
+    }
+
+    fn fingerprints(&self) -> Result<Option<HashMap<String, Fingerprint>>, SourceError> {
+        Ok(Some(
+            self.0
+                .iter()
+                .map(|(name, tpl)| (name.clone(), Fingerprint::of_content(tpl)))
+                .collect(),
+        ))
+    }
+
+    fn load_template(&self, name: &str, reg: &mut Handlebars) -> Result<(), SourceError> {
+        if let Some(tpl) = self.0.get(name) {
+            reg.register_template_string(name, tpl)?;
+        }
+        Ok(())
+    }
+
+    fn load_templates(
+        &self,
+        names: &[String],
+        reg: &mut Handlebars,
+    ) -> Vec<(Option<String>, SourceError)> {
+        source::load_each(self, names, reg)
+    }
 }
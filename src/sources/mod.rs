@@ -1,2 +1,102 @@
+use handlebars::template::{Parameter, Template, TemplateElement};
+use serde_json::value::Value as Json;
+use std::collections::HashSet;
+
+#[cfg(feature = "archive")]
+pub mod archive;
 pub mod directory;
+pub mod embedded;
 pub mod memory;
+pub mod overlay;
+pub mod prefixed;
+#[cfg(feature = "sqlite")]
+pub mod sqlite;
+
+/// rewrite names of partials included by `tpl`, `rename` returns the new
+/// name of a partial or `None` to keep it
+///
+/// Partials with names computed at render time, and inline partials
+/// defined by `tpl` with `{{#*inline}}`, are left untouched.
+pub(crate) fn rewrite_partials<F>(tpl: &mut Template, rename: &F)
+where
+    F: Fn(&str) -> Option<String>,
+{
+    let mut inline = HashSet::new();
+    inline_partials(tpl, &mut inline);
+    rename_partials(tpl, &|name: &str| {
+        if inline.contains(name) {
+            None
+        } else {
+            rename(name)
+        }
+    });
+}
+
+/// names of inline partials defined in `tpl`
+fn inline_partials(tpl: &Template, names: &mut HashSet<String>) {
+    for element in tpl.elements.iter() {
+        match *element {
+            TemplateElement::DecoratorBlock(ref d) => {
+                if d.name == Parameter::Name("inline".to_owned()) {
+                    if let Some(Parameter::Literal(Json::String(ref name))) = d.params.first() {
+                        names.insert(name.clone());
+                    }
+                }
+                if let Some(ref t) = d.template {
+                    inline_partials(t, names);
+                }
+            }
+            TemplateElement::DecoratorExpression(ref d)
+            | TemplateElement::PartialExpression(ref d)
+            | TemplateElement::PartialBlock(ref d) => {
+                if let Some(ref t) = d.template {
+                    inline_partials(t, names);
+                }
+            }
+            TemplateElement::HelperBlock(ref h) => {
+                for t in h.template.iter().chain(h.inverse.iter()) {
+                    inline_partials(t, names);
+                }
+            }
+            _ => (),
+        }
+    }
+}
+
+fn rename_partials<F>(tpl: &mut Template, rename: &F)
+where
+    F: Fn(&str) -> Option<String>,
+{
+    for element in tpl.elements.iter_mut() {
+        match *element {
+            TemplateElement::PartialExpression(ref mut d)
+            | TemplateElement::PartialBlock(ref mut d) => {
+                let renamed = match d.name {
+                    Parameter::Name(ref name) => rename(name),
+                    _ => None,
+                };
+                if let Some(name) = renamed {
+                    d.name = Parameter::Name(name);
+                }
+                if let Some(ref mut t) = d.template {
+                    rename_partials(t, rename);
+                }
+            }
+            TemplateElement::DecoratorExpression(ref mut d)
+            | TemplateElement::DecoratorBlock(ref mut d) => {
+                if let Some(ref mut t) = d.template {
+                    rename_partials(t, rename);
+                }
+            }
+            TemplateElement::HelperBlock(ref mut h) => {
+                if let Some(ref mut t) = h.template {
+                    rename_partials(t, rename);
+                }
+                if let Some(ref mut t) = h.inverse {
+                    rename_partials(t, rename);
+                }
+            }
+            _ => (),
+        }
+    }
+}
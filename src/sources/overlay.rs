@@ -0,0 +1,158 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::path::PathBuf;
+use std::sync::{Mutex, MutexGuard, PoisonError};
+
+use handlebars::Handlebars;
+use source::{self, Fingerprint, Source, SourceError, WatchTarget};
+use sources::directory::DirectorySource;
+use sources::rewrite_partials;
+
+/// name of the partial referring to the shadowed template
+const SUPER: &str = "super";
+
+/// A template file of a layer
+#[derive(Clone)]
+struct Entry {
+    path: PathBuf,
+    /// registered name of the template shadowed by this one
+    shadowed: Option<String>,
+}
+
+/// A source made of directory layers, like a theme overriding a base theme
+///
+/// Layers are ordered from highest to lowest priority: each template name
+/// is registered from the first layer containing it. An overriding
+/// template includes the one it shadows with `{{> super}}`; shadowed
+/// templates are registered under hidden names, `name@layer` where
+/// `layer` is the index of their directory.
+pub struct OverlaySource {
+    pub layers: Vec<DirectorySource>,
+    /// entries as of the last `fingerprints`
+    entries: Mutex<HashMap<String, Entry>>,
+}
+
+impl OverlaySource {
+    pub fn new<P>(layers: Vec<P>, suffix: &'static str) -> OverlaySource
+    where
+        P: Into<PathBuf>,
+    {
+        OverlaySource {
+            layers: layers
+                .into_iter()
+                .map(|prefix| DirectorySource::new(prefix, suffix))
+                .collect(),
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// directories of all layers, to be watched for changes
+    pub fn directories(&self) -> Vec<&PathBuf> {
+        self.layers.iter().map(|l| &l.prefix).collect()
+    }
+
+    /// template files of all layers, by registered name
+    /// entries of the last scan, still usable after a panic elsewhere
+    fn lock_entries(&self) -> MutexGuard<'_, HashMap<String, Entry>> {
+        self.entries.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn scan(&self) -> HashMap<String, Entry> {
+        let mut layered: BTreeMap<String, Vec<(usize, PathBuf)>> = BTreeMap::new();
+        for (i, layer) in self.layers.iter().enumerate() {
+            for (name, path) in layer.templates() {
+                layered.entry(name).or_default().push((i, path));
+            }
+        }
+
+        let mut entries = HashMap::new();
+        for (name, files) in layered {
+            let names: Vec<String> = files
+                .iter()
+                .enumerate()
+                .map(|(depth, &(layer, _))| match depth {
+                    0 => name.clone(),
+                    _ => format!("{}@{}", name, layer),
+                })
+                .collect();
+            for (depth, (_, path)) in files.into_iter().enumerate() {
+                let entry = Entry {
+                    path,
+                    shadowed: names.get(depth + 1).cloned(),
+                };
+                entries.insert(names[depth].clone(), entry);
+            }
+        }
+        entries
+    }
+
+    fn register(&self, name: &str, entry: &Entry, reg: &mut Handlebars) -> Result<(), SourceError> {
+        reg.register_template_file(name, &entry.path)?;
+        if let Some(ref shadowed) = entry.shadowed {
+            let mut tpl = reg.get_template(name).cloned().unwrap();
+            rewrite_partials(&mut tpl, &|partial| {
+                if partial == SUPER {
+                    Some(shadowed.clone())
+                } else {
+                    None
+                }
+            });
+            reg.register_template(name, tpl);
+        }
+        Ok(())
+    }
+}
+
+impl Source for OverlaySource {
+    fn load(&self, reg: &mut Handlebars) -> Result<(), SourceError> {
+        for (name, entry) in self.scan() {
+            self.register(&name, &entry, reg)?;
+        }
+        Ok(())
+    }
+
+    fn fingerprints(&self) -> Result<Option<HashMap<String, Fingerprint>>, SourceError> {
+        let entries = self.scan();
+        let mut fingerprints = HashMap::new();
+        for (name, entry) in entries.iter() {
+            // `{{> super}}` is resolved on load, so a template changes with
+            // the template it shadows
+            let shadowed = entry.shadowed.clone().unwrap_or_default();
+            let fingerprint = Fingerprint::combine(&[
+                Fingerprint::of_file(&fs::metadata(&entry.path)?),
+                Fingerprint::of_content(&shadowed),
+            ]);
+            fingerprints.insert(name.clone(), fingerprint);
+        }
+        *self.lock_entries() = entries;
+        Ok(Some(fingerprints))
+    }
+
+    fn load_template(&self, name: &str, reg: &mut Handlebars) -> Result<(), SourceError> {
+        let cached = self.lock_entries().get(name).cloned();
+        match cached.or_else(|| self.scan().remove(name)) {
+            Some(entry) => self.register(name, &entry, reg),
+            None => Ok(()),
+        }
+    }
+
+    fn load_templates(
+        &self,
+        names: &[String],
+        reg: &mut Handlebars,
+    ) -> Vec<(Option<String>, SourceError)> {
+        source::load_each(self, names, reg)
+    }
+
+    fn source_text(&self, name: &str) -> Option<String> {
+        fs::read_to_string(self.template_path(name)?).ok()
+    }
+
+    fn template_path(&self, name: &str) -> Option<PathBuf> {
+        self.scan().remove(name).map(|entry| entry.path)
+    }
+
+    fn watch_targets(&self) -> Vec<WatchTarget> {
+        self.layers.iter().flat_map(|l| l.watch_targets()).collect()
+    }
+}
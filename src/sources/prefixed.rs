@@ -0,0 +1,221 @@
+use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
+use std::sync::{Mutex, MutexGuard, PoisonError};
+
+use handlebars::{Handlebars, TemplateError};
+use source::{Fingerprint, Source, SourceError, WatchTarget};
+use sources::rewrite_partials;
+
+/// A source whose templates are registered under a name prefix
+///
+/// `Prefixed::new("admin/", source)` registers template `index` of
+/// `source` as `admin/index`. Partials referring to templates of the same
+/// source, like `{{> list}}`, are rewritten to the prefixed name, so a
+/// template pack works wherever it is mounted. Other partials, and partials
+/// with names computed at render time, are left untouched.
+pub struct Prefixed {
+    prefix: String,
+    source: Box<dyn Source + Send + Sync>,
+    /// template names of `source` as of the last `fingerprints`
+    names: Mutex<Option<HashSet<String>>>,
+}
+
+impl Prefixed {
+    pub fn new<S>(prefix: &str, source: S) -> Prefixed
+    where
+        S: Source + Send + Sync + 'static,
+    {
+        Prefixed::boxed(prefix, Box::new(source))
+    }
+
+    pub fn boxed(prefix: &str, source: Box<dyn Source + Send + Sync>) -> Prefixed {
+        Prefixed {
+            prefix: prefix.to_owned(),
+            source,
+            names: Mutex::new(None),
+        }
+    }
+
+    /// names of the inner source, ignoring poisoning by a panicked loader
+    fn lock_names(&self) -> MutexGuard<'_, Option<HashSet<String>>> {
+        self.names.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn inner_name<'a>(&self, name: &'a str) -> Option<&'a str> {
+        name.strip_prefix(self.prefix.as_str())
+    }
+
+    /// load templates with `load` into an empty copy of `reg`, and register
+    /// them into `reg` under prefixed names
+    fn load_with<F>(&self, reg: &mut Handlebars, load: F) -> Result<(), SourceError>
+    where
+        F: FnOnce(&mut Handlebars) -> Result<(), SourceError>,
+    {
+        let mut inner = reg.clone();
+        inner.clear_templates();
+        load(&mut inner).map_err(|e| self.prefix_error(e))?;
+        self.register(&inner, reg);
+        Ok(())
+    }
+
+    /// register templates of `inner` into `reg` under prefixed names
+    fn register(&self, inner: &Handlebars, reg: &mut Handlebars) {
+        let names = self.lock_names().clone();
+        let names = names.unwrap_or_else(|| inner.get_templates().keys().cloned().collect());
+        for (name, tpl) in inner.get_templates() {
+            let mut tpl = tpl.clone();
+            let name = format!("{}{}", self.prefix, name);
+            tpl.name = Some(name.clone());
+            rewrite_partials(&mut tpl, &|partial| {
+                if names.contains(partial) {
+                    Some(format!("{}{}", self.prefix, partial))
+                } else {
+                    None
+                }
+            });
+            reg.register_template(&name, tpl);
+        }
+    }
+
+    /// report template errors with prefixed names
+    fn prefix_error(&self, e: SourceError) -> SourceError {
+        match e.cause.downcast::<TemplateError>() {
+            Ok(mut err) => {
+                err.template_name = err
+                    .template_name
+                    .map(|name| format!("{}{}", self.prefix, name));
+                SourceError { cause: err }
+            }
+            Err(cause) => SourceError { cause },
+        }
+    }
+}
+
+impl Source for Prefixed {
+    fn load(&self, reg: &mut Handlebars) -> Result<(), SourceError> {
+        self.load_with(reg, |inner| self.source.load(inner))
+    }
+
+    fn fingerprints(&self) -> Result<Option<HashMap<String, Fingerprint>>, SourceError> {
+        let fingerprints = match self.source.fingerprints()? {
+            Some(fingerprints) => fingerprints,
+            None => {
+                *self.lock_names() = None;
+                return Ok(None);
+            }
+        };
+        // partials are rewritten against the names of the whole source, so
+        // every template changes when they do
+        let mut names: Vec<&String> = fingerprints.keys().collect();
+        names.sort();
+        let names_fingerprint = Fingerprint::of_content(&format!("{:?}", names));
+        *self.lock_names() = Some(fingerprints.keys().cloned().collect());
+        Ok(Some(
+            fingerprints
+                .into_iter()
+                .map(|(name, f)| {
+                    let f = Fingerprint::combine(&[f, names_fingerprint.clone()]);
+                    (format!("{}{}", self.prefix, name), f)
+                })
+                .collect(),
+        ))
+    }
+
+    fn load_template(&self, name: &str, reg: &mut Handlebars) -> Result<(), SourceError> {
+        // partials are resolved against names of the whole source
+        if self.lock_names().is_none() {
+            self.fingerprints()?;
+        }
+        match self.inner_name(name) {
+            Some(name) => self.load_with(reg, |inner| self.source.load_template(name, inner)),
+            None => Ok(()),
+        }
+    }
+
+    fn load_templates(
+        &self,
+        names: &[String],
+        reg: &mut Handlebars,
+    ) -> Vec<(Option<String>, SourceError)> {
+        if self.lock_names().is_none() {
+            if let Err(e) = self.fingerprints() {
+                return vec![(None, e)];
+            }
+        }
+        let names: Vec<String> = names
+            .iter()
+            .filter_map(|name| self.inner_name(name))
+            .map(|name| name.to_owned())
+            .collect();
+        let mut inner = reg.clone();
+        inner.clear_templates();
+        let failed = self.source.load_templates(&names, &mut inner);
+        self.register(&inner, reg);
+        failed
+            .into_iter()
+            .map(|(name, e)| {
+                let name = name.map(|name| format!("{}{}", self.prefix, name));
+                (name, self.prefix_error(e))
+            })
+            .collect()
+    }
+
+    fn source_text(&self, name: &str) -> Option<String> {
+        self.source.source_text(self.inner_name(name)?)
+    }
+
+    fn template_path(&self, name: &str) -> Option<PathBuf> {
+        self.source.template_path(self.inner_name(name)?)
+    }
+
+    fn watch_targets(&self) -> Vec<WatchTarget> {
+        self.source.watch_targets()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use sources::memory::MemorySource;
+    use std::collections::BTreeMap;
+
+    #[test]
+    fn test_prefixed_partials() {
+        let mut templates = BTreeMap::new();
+        templates.insert(
+            "index".to_owned(),
+            "{{> item}}{{#if x}}{{> item}}{{/if}}{{> global}}".to_owned(),
+        );
+        templates.insert("item".to_owned(), "admin item".to_owned());
+        let mut reg = Handlebars::new();
+        reg.register_template_string("item", "app item").unwrap();
+        reg.register_template_string("global", " global").unwrap();
+
+        Prefixed::new("admin/", MemorySource(templates))
+            .load(&mut reg)
+            .unwrap();
+        assert!(reg.get_template("index").is_none());
+        assert_eq!(
+            reg.render("admin/index", &json!({"x": true})).unwrap(),
+            "admin itemadmin item global"
+        );
+        assert_eq!(reg.render("item", &()).unwrap(), "app item");
+    }
+
+    #[test]
+    fn test_prefixed_inline_partials() {
+        let mut templates = BTreeMap::new();
+        templates.insert(
+            "index".to_owned(),
+            "{{#*inline \"item\"}}inline item{{/inline}}{{> item}} {{> list}}".to_owned(),
+        );
+        templates.insert("item".to_owned(), "admin item".to_owned());
+        templates.insert("list".to_owned(), "list".to_owned());
+        let mut reg = Handlebars::new();
+
+        Prefixed::new("admin/", MemorySource(templates))
+            .load(&mut reg)
+            .unwrap();
+        assert_eq!(reg.render("admin/index", &()).unwrap(), "inline item list");
+    }
+}
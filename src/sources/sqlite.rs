@@ -0,0 +1,211 @@
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::{Mutex, MutexGuard, PoisonError};
+use std::time::Duration;
+
+use handlebars::Handlebars;
+use rusqlite::types::Value;
+use rusqlite::{params, params_from_iter, Connection};
+use source::{Fingerprint, Source, SourceError, WatchTarget};
+
+impl From<rusqlite::Error> for SourceError {
+    fn from(err: rusqlite::Error) -> SourceError {
+        SourceError {
+            cause: Box::new(err),
+        }
+    }
+}
+
+/// A source loading templates from a SQLite database, with feature `sqlite`
+///
+/// Templates are rows of `(name, body, updated_at)`, selected from a
+/// table with these columns or by a custom query returning them in this
+/// order. Only templates whose `updated_at` changed are loaded again on
+/// reload, so reloading often, like with `Pollable::poll` or
+/// `Watchable::watch_sources`, is cheap.
+pub struct SqliteSource {
+    conn: Mutex<Connection>,
+    query: String,
+    poll_interval: Duration,
+}
+
+impl SqliteSource {
+    /// load templates from `table` of database file `path`
+    pub fn open<P>(path: P, table: &str) -> Result<SqliteSource, SourceError>
+    where
+        P: AsRef<Path>,
+    {
+        Ok(SqliteSource::new(Connection::open(path)?, table))
+    }
+
+    /// load templates from `table`, with columns `name`, `body` and
+    /// `updated_at`
+    pub fn new(conn: Connection, table: &str) -> SqliteSource {
+        let query = format!(
+            "SELECT name, body, updated_at FROM \"{}\"",
+            table.replace('"', "\"\"")
+        );
+        SqliteSource::with_query(conn, &query)
+    }
+
+    /// load templates selected by `query`, which returns name, body and
+    /// update time of templates, in this order
+    pub fn with_query(conn: Connection, query: &str) -> SqliteSource {
+        SqliteSource {
+            conn: Mutex::new(conn),
+            query: query.to_owned(),
+            poll_interval: Duration::from_secs(10),
+        }
+    }
+
+    /// how often `Watchable::watch_sources` reloads this source, every 10
+    /// seconds by default
+    pub fn poll_interval(mut self, interval: Duration) -> SqliteSource {
+        self.poll_interval = interval;
+        self
+    }
+
+    fn conn(&self) -> MutexGuard<'_, Connection> {
+        self.conn.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// `select` from the templates of `query`, as table `templates`
+    fn templates_sql(&self, select: &str) -> String {
+        format!(
+            "WITH templates(name, body, updated_at) AS ({}) {}",
+            self.query, select
+        )
+    }
+
+    /// names and bodies of templates `names`, in a single query
+    fn bodies(&self, names: &[String]) -> Result<Vec<(String, String)>, SourceError> {
+        let conn = self.conn();
+        let placeholders = vec!["?"; names.len()].join(", ");
+        let sql = self.templates_sql(&format!(
+            "SELECT name, body FROM templates WHERE name IN ({})",
+            placeholders
+        ));
+        let mut stmt = conn.prepare(&sql)?;
+        let rows = stmt.query_map(params_from_iter(names), |row| {
+            Ok((row.get(0)?, row.get(1)?))
+        })?;
+        Ok(rows.collect::<Result<_, _>>()?)
+    }
+
+    fn body(&self, name: &str) -> Result<Option<String>, SourceError> {
+        let conn = self.conn();
+        let sql = self.templates_sql("SELECT name, body FROM templates WHERE name = ?1");
+        let mut stmt = conn.prepare(&sql)?;
+        let mut rows = stmt.query(params![name])?;
+        match rows.next()? {
+            Some(row) => Ok(Some(row.get(1)?)),
+            None => Ok(None),
+        }
+    }
+}
+
+impl Source for SqliteSource {
+    fn load(&self, reg: &mut Handlebars) -> Result<(), SourceError> {
+        let conn = self.conn();
+        let mut stmt = conn.prepare(&self.query)?;
+        let mut rows = stmt.query([])?;
+        while let Some(row) = rows.next()? {
+            let name: String = row.get(0)?;
+            let body: String = row.get(1)?;
+            reg.register_template_string(&name, body)?;
+        }
+        Ok(())
+    }
+
+    fn fingerprints(&self) -> Result<Option<HashMap<String, Fingerprint>>, SourceError> {
+        // bodies are only read for changed templates, by `load_templates`
+        let conn = self.conn();
+        let sql = self.templates_sql("SELECT name, updated_at FROM templates");
+        let mut stmt = conn.prepare(&sql)?;
+        let mut rows = stmt.query([])?;
+        let mut fingerprints = HashMap::new();
+        while let Some(row) = rows.next()? {
+            let name: String = row.get(0)?;
+            let updated_at: Value = row.get(1)?;
+            let fingerprint = Fingerprint::of_content(&format!("{:?}", updated_at));
+            fingerprints.insert(name, fingerprint);
+        }
+        Ok(Some(fingerprints))
+    }
+
+    fn load_template(&self, name: &str, reg: &mut Handlebars) -> Result<(), SourceError> {
+        if let Some(body) = self.body(name)? {
+            reg.register_template_string(name, body)?;
+        }
+        Ok(())
+    }
+
+    fn load_templates(
+        &self,
+        names: &[String],
+        reg: &mut Handlebars,
+    ) -> Vec<(Option<String>, SourceError)> {
+        let mut failed = Vec::new();
+        // stay below the limit of query parameters
+        for names in names.chunks(500) {
+            match self.bodies(names) {
+                Ok(bodies) => {
+                    for (name, body) in bodies {
+                        if let Err(e) = reg.register_template_string(&name, body) {
+                            failed.push((Some(name), e.into()));
+                        }
+                    }
+                }
+                Err(e) => failed.push((None, e)),
+            }
+        }
+        failed
+    }
+
+    fn source_text(&self, name: &str) -> Option<String> {
+        self.body(name).ok()?
+    }
+
+    fn watch_targets(&self) -> Vec<WatchTarget> {
+        vec![WatchTarget::Poll(self.poll_interval)]
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_sqlite_source() {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(
+            "CREATE TABLE pages (slug TEXT, html TEXT, changed INTEGER);
+             INSERT INTO pages VALUES ('index', 'v1', 1), ('about', 'about', 1);",
+        )
+        .unwrap();
+        let source = SqliteSource::with_query(conn, "SELECT slug, html, changed FROM pages");
+
+        let fingerprints = source.fingerprints().unwrap().unwrap();
+        assert_eq!(fingerprints.len(), 2);
+        let mut reg = Handlebars::new();
+        source.load_template("index", &mut reg).unwrap();
+        assert_eq!(reg.render("index", &()).unwrap(), "v1");
+
+        source
+            .conn()
+            .execute(
+                "UPDATE pages SET html = 'v2', changed = 2 WHERE slug = 'index'",
+                [],
+            )
+            .unwrap();
+        let changed = source.fingerprints().unwrap().unwrap();
+        assert_ne!(changed["index"], fingerprints["index"]);
+        assert_eq!(changed["about"], fingerprints["about"]);
+        assert_eq!(source.source_text("index"), Some("v2".to_owned()));
+
+        let names = vec!["index".to_owned(), "about".to_owned()];
+        assert!(source.load_templates(&names, &mut reg).is_empty());
+        assert_eq!(reg.render("index", &()).unwrap(), "v2");
+        assert_eq!(reg.render("about", &()).unwrap(), "about");
+    }
+}
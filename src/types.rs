@@ -0,0 +1,136 @@
+use std::collections::HashMap;
+
+use handlebars::html_escape;
+use iron::mime::Mime;
+
+/// Escaping applied to `{{expression}}` output of a template
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Escape {
+    /// the escape function of the registry, html escaping unless another
+    /// function is registered
+    Default,
+    /// html escaping
+    Html,
+    /// xml escaping, including quotes
+    Xml,
+    /// quote values that contain delimiters, quotes or line breaks
+    Csv,
+    /// no escaping at all
+    NoEscape,
+}
+
+impl Escape {
+    pub(crate) fn escape_fn(self) -> Option<fn(&str) -> String> {
+        match self {
+            Escape::Default => None,
+            Escape::Html => Some(html_escape),
+            Escape::Xml => Some(xml_escape),
+            Escape::Csv => Some(csv_escape),
+            Escape::NoEscape => Some(no_escape),
+        }
+    }
+}
+
+fn xml_escape(data: &str) -> String {
+    let mut out = String::with_capacity(data.len());
+    for c in data.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&apos;"),
+            _ => out.push(c),
+        }
+    }
+    out
+}
+
+fn csv_escape(data: &str) -> String {
+    if data.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", data.replace('"', "\"\""))
+    } else {
+        data.to_owned()
+    }
+}
+
+fn no_escape(data: &str) -> String {
+    data.to_owned()
+}
+
+/// Content type and escaping derived from template name extension
+///
+/// A template named `feed.xml`, for example loaded from `feed.xml.hbs` by
+/// `DirectorySource`, is sent as `application/xml` and rendered with xml
+/// escaping. Templates whose extension is not in the table are sent as
+/// `text/html` with the registry's escape function.
+///
+/// Built-in extensions are `html`, `htm`, `xml`, `txt` and `csv`; use
+/// `insert` to add your own.
+#[derive(Clone, Debug)]
+pub struct ExtensionTypes {
+    types: HashMap<String, (Mime, Escape)>,
+}
+
+impl ExtensionTypes {
+    /// create an empty table, which disables inference
+    pub fn new() -> ExtensionTypes {
+        ExtensionTypes {
+            types: HashMap::new(),
+        }
+    }
+
+    /// map an extension, without leading dot, to content type and escaping
+    pub fn insert(&mut self, extension: &str, mime: Mime, escape: Escape) {
+        self.types.insert(extension.to_owned(), (mime, escape));
+    }
+
+    /// remove an extension from the table
+    pub fn remove(&mut self, extension: &str) {
+        self.types.remove(extension);
+    }
+
+    /// content type and escaping for a template name
+    pub fn get(&self, name: &str) -> Option<&(Mime, Escape)> {
+        let file = name.rsplit('/').next().unwrap_or(name);
+        file.rfind('.')
+            .and_then(|idx| self.types.get(&file[idx + 1..]))
+    }
+}
+
+impl Default for ExtensionTypes {
+    fn default() -> ExtensionTypes {
+        let mut types = ExtensionTypes::new();
+        for &(ext, mime, escape) in &[
+            ("html", "text/html; charset=utf-8", Escape::Default),
+            ("htm", "text/html; charset=utf-8", Escape::Default),
+            ("xml", "application/xml; charset=utf-8", Escape::Xml),
+            ("txt", "text/plain; charset=utf-8", Escape::NoEscape),
+            ("csv", "text/csv; charset=utf-8", Escape::Csv),
+        ] {
+            types.insert(ext, mime.parse().unwrap(), escape);
+        }
+        types
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_lookup() {
+        let types = ExtensionTypes::default();
+        assert_eq!(types.get("feed.xml").unwrap().1, Escape::Xml);
+        assert_eq!(types.get("mail/welcome.txt").unwrap().1, Escape::NoEscape);
+        assert!(types.get("index").is_none());
+        assert!(types.get("v1.2/index").is_none());
+    }
+
+    #[test]
+    fn test_escape() {
+        assert_eq!(xml_escape("<a href='x'>"), "&lt;a href=&apos;x&apos;&gt;");
+        assert_eq!(csv_escape("plain"), "plain");
+        assert_eq!(csv_escape("a,\"b\""), "\"a,\"\"b\"\"\"");
+    }
+}
@@ -1,39 +1,681 @@
 use middleware::HandlebarsEngine;
+use source::{Fingerprint, WatchTarget};
 
-use notify::{Error, RecommendedWatcher, RecursiveMode, Watcher};
-use std::path::Path;
-use std::sync::mpsc::channel;
+use notify::{DebouncedEvent, Error, RecommendedWatcher, RecursiveMode, Watcher};
+use std::cmp;
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError};
 use std::sync::Arc;
-use std::thread;
-use std::time::Duration;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+use walkdir::WalkDir;
 
-fn _watch(p: &Path, hbs: &Arc<HandlebarsEngine>) -> Result<(), Error> {
+/// how often the watcher thread checks whether it was stopped
+const TICK: Duration = Duration::from_millis(100);
+
+/// How a watcher notices changes of files
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WatchMode {
+    /// file system notifications, like inotify
+    Native,
+    /// compare modification time and size of watched files at an
+    /// interval, for file systems without notifications like NFS or
+    /// some container mounts
+    Poll(Duration),
+    /// file system notifications, checked by polling at an interval;
+    /// switches to polling when notifications can't be set up or miss
+    /// changes
+    Fallback(Duration),
+}
+
+/// Timing of a watcher
+#[derive(Clone, Debug)]
+pub struct WatchOptions {
+    mode: WatchMode,
+    debounce: Duration,
+    retry_min: Duration,
+    retry_max: Duration,
+}
+
+impl Default for WatchOptions {
+    fn default() -> WatchOptions {
+        WatchOptions {
+            mode: WatchMode::Native,
+            debounce: Duration::from_secs(2),
+            retry_min: Duration::from_secs(1),
+            retry_max: Duration::from_secs(60),
+        }
+    }
+}
+
+impl WatchOptions {
+    pub fn new() -> WatchOptions {
+        WatchOptions::default()
+    }
+
+    /// how to notice changes, `WatchMode::Native` by default
+    pub fn mode(mut self, mode: WatchMode) -> WatchOptions {
+        self.mode = mode;
+        self
+    }
+
+    /// how long to wait for changes to settle before reloading, 2 seconds
+    /// by default
+    pub fn debounce(mut self, debounce: Duration) -> WatchOptions {
+        self.debounce = debounce;
+        self
+    }
+
+    /// delays between attempts to set up the watcher again after it
+    /// failed, doubling from `min` up to `max`; 1 and 60 seconds by
+    /// default
+    pub fn retry_backoff(mut self, min: Duration, max: Duration) -> WatchOptions {
+        self.retry_min = min;
+        self.retry_max = max;
+        self
+    }
+}
+
+/// A running watcher, returned by `Watchable` methods
+///
+/// Dropping the handle leaves the watcher running, call `stop` to end it.
+pub struct WatchHandle {
+    stopped: Arc<AtomicBool>,
+    thread: JoinHandle<()>,
+}
+
+impl WatchHandle {
+    /// stop watching, the watcher thread ends shortly after
+    pub fn stop(&self) {
+        self.stopped.store(true, Ordering::SeqCst);
+    }
+
+    /// wait for the watcher thread to end, which happens only after `stop`
+    pub fn join(self) -> thread::Result<()> {
+        self.thread.join()
+    }
+}
+
+/// Sources to reload when a target changes
+#[derive(Clone, Copy)]
+enum Scope {
+    All,
+    Source(usize),
+}
+
+/// A target being watched, with its canonical path
+struct Watched {
+    path: PathBuf,
+    recursive: bool,
+    scope: Scope,
+    /// templates as of the last poll
+    snapshot: Fingerprint,
+    /// a notification arrived since the last poll
+    notified: bool,
+    /// when a poll found a change no notification reported yet
+    unnotified: Option<Instant>,
+}
+
+impl Watched {
+    fn new(target: &WatchTarget, scope: Scope) -> Result<Option<Watched>, Error> {
+        let watched = match *target {
+            WatchTarget::Directory(ref dir) => Watched {
+                path: dir.canonicalize()?,
+                recursive: true,
+                scope,
+                snapshot: Fingerprint::combine(&[]),
+                notified: false,
+                unnotified: None,
+            },
+            // watch the directory of a file, to notice it being replaced
+            WatchTarget::File(ref file) => {
+                let dir = match file.parent() {
+                    Some(dir) if !dir.as_os_str().is_empty() => dir,
+                    _ => Path::new("."),
+                };
+                Watched {
+                    path: dir
+                        .canonicalize()?
+                        .join(file.file_name().unwrap_or_default()),
+                    recursive: false,
+                    scope,
+                    snapshot: Fingerprint::combine(&[]),
+                    notified: false,
+                    unnotified: None,
+                }
+            }
+            WatchTarget::Poll(_) => return Ok(None),
+        };
+        Ok(Some(watched))
+    }
+
+    fn watch_path(&self) -> &Path {
+        if self.recursive {
+            &self.path
+        } else {
+            self.path.parent().unwrap_or(&self.path)
+        }
+    }
+
+    fn matches(&self, path: &Path) -> bool {
+        if self.recursive {
+            path.starts_with(&self.path)
+        } else {
+            path == self.path
+        }
+    }
+
+    /// fingerprint of watched templates
+    ///
+    /// Directories of a source are scanned with `Source::fingerprints`, so
+    /// only its templates count. Other targets, and sources without
+    /// fingerprints, by paths, modification times and sizes of all files.
+    fn scan(&self, hbs: &HandlebarsEngine) -> Fingerprint {
+        if let (true, Scope::Source(i)) = (self.recursive, self.scope) {
+            if let Some(Ok(Some(fingerprints))) = hbs.sources().get(i).map(|s| s.fingerprints()) {
+                let mut fingerprints: Vec<_> = fingerprints.into_iter().collect();
+                fingerprints.sort_by(|a, b| a.0.cmp(&b.0));
+                let parts: Vec<Fingerprint> = fingerprints
+                    .into_iter()
+                    .flat_map(|(name, f)| vec![Fingerprint::of_content(&name), f])
+                    .collect();
+                return Fingerprint::combine(&parts);
+            }
+        }
+
+        let depth = if self.recursive { usize::MAX } else { 0 };
+        let mut parts = Vec::new();
+        for entry in WalkDir::new(&self.path)
+            .max_depth(depth)
+            .sort_by(|a, b| a.file_name().cmp(b.file_name()))
+            .into_iter()
+            .filter_map(|e| e.ok())
+        {
+            if let Ok(metadata) = entry.metadata() {
+                if metadata.is_file() {
+                    parts.push(Fingerprint::of_content(&entry.path().to_string_lossy()));
+                    parts.push(Fingerprint::of_file(&metadata));
+                }
+            }
+        }
+        Fingerprint::combine(&parts)
+    }
+}
+
+/// A source reloaded periodically
+struct Polled {
+    interval: Duration,
+    next: Instant,
+    scope: Scope,
+}
+
+fn event_paths(event: &DebouncedEvent) -> Vec<&Path> {
+    match *event {
+        DebouncedEvent::Create(ref p)
+        | DebouncedEvent::Write(ref p)
+        | DebouncedEvent::Chmod(ref p)
+        | DebouncedEvent::Remove(ref p) => vec![p],
+        DebouncedEvent::Rename(ref from, ref to) => vec![from, to],
+        _ => Vec::new(),
+    }
+}
+
+/// whether `event` removed, renamed or recreated a watched directory
+/// itself, like a deploy replacing `templates/`
+fn root_lost(watched: &[Watched], event: &DebouncedEvent) -> bool {
+    match *event {
+        DebouncedEvent::NoticeRemove(ref path)
+        | DebouncedEvent::Remove(ref path)
+        | DebouncedEvent::Rename(ref path, _)
+        | DebouncedEvent::Create(ref path) => watched.iter().any(|w| w.watch_path() == path),
+        _ => false,
+    }
+}
+
+fn reload(hbs: &HandlebarsEngine, scopes: &[Scope]) {
+    let mut indices = BTreeSet::new();
+    let mut all = false;
+    for scope in scopes {
+        match *scope {
+            Scope::All => all = true,
+            Scope::Source(i) => {
+                indices.insert(i);
+            }
+        }
+    }
+    let result = if all {
+        hbs.reload()
+    } else {
+        hbs.reload_sources(&indices.into_iter().collect::<Vec<_>>())
+    };
+    match result {
+        Ok(report) => info!("Reloaded templates: {}", report),
+        Err(report) => error!("Failed to reload templates: {}", report),
+    }
+}
+
+type Events = (RecommendedWatcher, Receiver<DebouncedEvent>);
+
+/// paths to watch, each watched recursively if any target needs it
+fn notify_paths(watched: &[Watched]) -> BTreeMap<&Path, bool> {
+    let mut paths = BTreeMap::new();
+    for w in watched.iter() {
+        *paths.entry(w.watch_path()).or_insert(false) |= w.recursive;
+    }
+    paths
+}
+
+/// start a notify watcher for all watched paths
+fn setup(watched: &[Watched], debounce: Duration) -> Result<Events, Error> {
     let (tx, rx) = channel();
-    let mut watcher: RecommendedWatcher = try!(Watcher::new(tx, Duration::from_secs(2)));
-    try!(watcher.watch(p, RecursiveMode::Recursive));
-    loop {
-        let _ = rx.recv();
-        info!("Template directory changed");
-        if let Err(e) = hbs.reload() {
-            error!("Failed to reload directory: {:?}", e);
+    let mut watcher: RecommendedWatcher = Watcher::new(tx, debounce)?;
+    for (path, recursive) in notify_paths(watched) {
+        let mode = if recursive {
+            RecursiveMode::Recursive
+        } else {
+            RecursiveMode::NonRecursive
+        };
+        watcher.watch(path, mode)?;
+    }
+    Ok((watcher, rx))
+}
+
+struct Watch {
+    hbs: Arc<HandlebarsEngine>,
+    options: WatchOptions,
+    watched: Vec<Watched>,
+    polled: Vec<Polled>,
+    stopped: Arc<AtomicBool>,
+    /// whether to use file system notifications
+    native: bool,
+    next_scan: Instant,
+}
+
+impl Watch {
+    fn run(mut self, mut events: Option<Events>) {
+        let mut retry = self.options.retry_min;
+        let mut next_retry = Instant::now();
+
+        while !self.stopped.load(Ordering::SeqCst) {
+            if self.native && events.is_none() && next_retry <= Instant::now() {
+                match setup(&self.watched, self.options.debounce) {
+                    Ok(e) => {
+                        info!("Watching templates again");
+                        events = Some(e);
+                        retry = self.options.retry_min;
+                        // changes may have been missed in the meantime
+                        let scopes: Vec<Scope> = self.watched.iter().map(|w| w.scope).collect();
+                        reload(&self.hbs, &scopes);
+                    }
+                    Err(e) => {
+                        warn!(
+                            "Failed to watch templates, retrying in {:?}: {:?}",
+                            retry, e
+                        );
+                        next_retry = Instant::now() + retry;
+                        retry = cmp::min(retry * 2, self.options.retry_max);
+                    }
+                }
+            }
+
+            let mut scopes = Vec::new();
+            let received = match events {
+                Some((_, ref rx)) => rx.recv_timeout(self.timeout()),
+                None => {
+                    thread::sleep(self.timeout());
+                    Err(RecvTimeoutError::Timeout)
+                }
+            };
+            match received {
+                Ok(DebouncedEvent::Rescan) => {
+                    for w in self.watched.iter_mut() {
+                        w.notified = true;
+                        scopes.push(w.scope);
+                    }
+                }
+                // errors on single paths, like a file removed while being
+                // watched, leave the watcher working
+                Ok(DebouncedEvent::Error(e, Some(path))) => {
+                    warn!("Template watcher error on {:?}: {:?}", path, e);
+                }
+                Ok(DebouncedEvent::Error(e, None)) => {
+                    warn!("Template watcher failed: {:?}", e);
+                    events = None;
+                }
+                Ok(event) => {
+                    for path in event_paths(&event) {
+                        for w in self.watched.iter_mut().filter(|w| w.matches(path)) {
+                            w.notified = true;
+                            scopes.push(w.scope);
+                        }
+                    }
+                    if !scopes.is_empty() {
+                        info!("Templates changed: {:?}", event);
+                    }
+                    // notifications follow the old directory, watch the
+                    // path again
+                    if root_lost(&self.watched, &event) {
+                        warn!("Watched template directory went away: {:?}", event);
+                        events = None;
+                    }
+                }
+                Err(RecvTimeoutError::Timeout) => (),
+                Err(RecvTimeoutError::Disconnected) => {
+                    warn!("Template watcher stopped unexpectedly");
+                    events = None;
+                }
+            }
+
+            let now = Instant::now();
+            if let Some(interval) = self.scan_interval() {
+                if self.next_scan <= now {
+                    scopes.extend(self.scan(events.is_some()));
+                    self.next_scan = now + interval;
+                    if !self.native {
+                        events = None;
+                    }
+                }
+            }
+            for p in self.polled.iter_mut().filter(|p| p.next <= now) {
+                scopes.push(p.scope);
+                p.next = now + p.interval;
+            }
+            if !scopes.is_empty() {
+                reload(&self.hbs, &scopes);
+            }
+        }
+    }
+
+    fn scan_interval(&self) -> Option<Duration> {
+        match self.options.mode {
+            WatchMode::Native => None,
+            WatchMode::Poll(interval) | WatchMode::Fallback(interval) => Some(interval),
+        }
+    }
+
+    /// poll watched files, returning the scopes to reload
+    ///
+    /// While notifications are working, changes are left to them. A
+    /// change they still didn't report after a poll interval plus the
+    /// debounce means they don't work on this file system, the watcher
+    /// switches to polling then.
+    fn scan(&mut self, notifying: bool) -> Vec<Scope> {
+        let grace = self.scan_interval().unwrap_or_default() + self.options.debounce;
+        let now = Instant::now();
+        let hbs = &self.hbs;
+        let mut scopes = Vec::new();
+        let mut missed = false;
+        for w in self.watched.iter_mut() {
+            let snapshot = w.scan(hbs);
+            let changed = snapshot != w.snapshot;
+            w.snapshot = snapshot;
+            if !notifying {
+                if changed {
+                    scopes.push(w.scope);
+                }
+            } else if w.notified {
+                w.unnotified = None;
+            } else if let Some(since) = w.unnotified {
+                if now.duration_since(since) >= grace {
+                    missed = true;
+                    scopes.push(w.scope);
+                    w.unnotified = None;
+                }
+            } else if changed {
+                w.unnotified = Some(now);
+            }
+            w.notified = false;
+        }
+        if missed {
+            warn!("File system notifications missed template changes, polling instead");
+            self.native = false;
+        }
+        scopes
+    }
+
+    /// how long to wait for events before checking for polls and stop
+    fn timeout(&self) -> Duration {
+        let scan = self.scan_interval().map(|_| self.next_scan);
+        self.polled
+            .iter()
+            .map(|p| p.next)
+            .chain(scan)
+            .map(|next| next.saturating_duration_since(Instant::now()))
+            .fold(TICK, cmp::min)
+    }
+}
+
+fn spawn_watch(
+    hbs: &Arc<HandlebarsEngine>,
+    targets: Vec<(WatchTarget, Scope)>,
+    options: WatchOptions,
+) -> Result<WatchHandle, Error> {
+    let mut watched = Vec::new();
+    let mut polled = Vec::new();
+    for (target, scope) in targets {
+        if let WatchTarget::Poll(interval) = target {
+            polled.push(Polled {
+                interval,
+                next: Instant::now() + interval,
+                scope,
+            });
+        } else if let Some(w) = Watched::new(&target, scope)? {
+            watched.push(w);
+        }
+    }
+
+    let mut native = true;
+    let mut next_scan = Instant::now();
+    if let WatchMode::Poll(interval) | WatchMode::Fallback(interval) = options.mode {
+        for w in watched.iter_mut() {
+            w.snapshot = w.scan(hbs);
         }
+        native = options.mode != WatchMode::Poll(interval);
+        next_scan += interval;
     }
+    let events = if native {
+        match setup(&watched, options.debounce) {
+            Ok(events) => Some(events),
+            Err(e) if options.mode != WatchMode::Native => {
+                warn!("Failed to watch templates, polling instead: {:?}", e);
+                native = false;
+                None
+            }
+            Err(e) => return Err(e),
+        }
+    } else {
+        None
+    };
+
+    let stopped = Arc::new(AtomicBool::new(false));
+    let watch = Watch {
+        hbs: hbs.clone(),
+        options,
+        watched,
+        polled,
+        stopped: stopped.clone(),
+        native,
+        next_scan,
+    };
+    let thread = thread::spawn(move || watch.run(events));
+    Ok(WatchHandle { stopped, thread })
 }
 
 pub trait Watchable {
-    fn watch(&self, path: &str);
+    fn watch(&self, path: &str) -> Result<WatchHandle, Error>;
+
+    /// reload templates when any of `paths` changes, like all layers of
+    /// an `OverlaySource`
+    fn watch_paths(&self, paths: &[&Path]) -> Result<WatchHandle, Error>;
+
+    /// reload templates when file `path` changes or gets replaced, like
+    /// the archive of an `ArchiveSource`
+    fn watch_file(&self, path: &Path) -> Result<WatchHandle, Error>;
+
+    /// watch targets of all sources, see `Source::watch_targets`, and
+    /// reload only the sources whose targets changed
+    fn watch_sources(&self) -> Result<WatchHandle, Error> {
+        self.watch_sources_with(WatchOptions::default())
+    }
+
+    /// like `watch_sources`, with custom timing
+    fn watch_sources_with(&self, options: WatchOptions) -> Result<WatchHandle, Error>;
+
+    /// reload all templates when any of `targets` changes, with custom
+    /// timing
+    fn watch_with(
+        &self,
+        targets: &[WatchTarget],
+        options: WatchOptions,
+    ) -> Result<WatchHandle, Error>;
 }
 
 impl Watchable for Arc<HandlebarsEngine> {
-    fn watch(&self, path: &str) {
-        let hbs = self.clone();
-        let watch_path = path.to_owned();
-        thread::spawn(move || match _watch(Path::new(&watch_path), &hbs) {
-            Ok(_) => (),
-            Err(e) => {
-                warn!("Failed to watch directory: {:?}", e);
-                panic!();
-            }
-        });
+    fn watch(&self, path: &str) -> Result<WatchHandle, Error> {
+        self.watch_paths(&[Path::new(path)])
+    }
+
+    fn watch_paths(&self, paths: &[&Path]) -> Result<WatchHandle, Error> {
+        let targets: Vec<WatchTarget> = paths
+            .iter()
+            .map(|p| WatchTarget::Directory(p.to_path_buf()))
+            .collect();
+        self.watch_with(&targets, WatchOptions::default())
+    }
+
+    fn watch_file(&self, path: &Path) -> Result<WatchHandle, Error> {
+        let target = WatchTarget::File(path.to_path_buf());
+        self.watch_with(&[target], WatchOptions::default())
+    }
+
+    fn watch_sources_with(&self, options: WatchOptions) -> Result<WatchHandle, Error> {
+        let targets = self
+            .sources()
+            .iter()
+            .enumerate()
+            .flat_map(|(i, s)| {
+                s.watch_targets()
+                    .into_iter()
+                    .map(move |t| (t, Scope::Source(i)))
+            })
+            .collect();
+        spawn_watch(self, targets, options)
+    }
+
+    fn watch_with(
+        &self,
+        targets: &[WatchTarget],
+        options: WatchOptions,
+    ) -> Result<WatchHandle, Error> {
+        let targets = targets.iter().map(|t| (t.clone(), Scope::All)).collect();
+        spawn_watch(self, targets, options)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use sources::directory::DirectorySource;
+    use std::env;
+    use std::fs;
+    use std::process;
+
+    #[test]
+    fn test_notify_paths() {
+        let dir = env::temp_dir().join(format!("hbsi-watch-paths-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let targets = [
+            WatchTarget::File(dir.join("bundle.tar")),
+            WatchTarget::Directory(dir.clone()),
+        ];
+        let watched: Vec<Watched> = targets
+            .iter()
+            .filter_map(|t| Watched::new(t, Scope::All).unwrap())
+            .collect();
+        let paths = notify_paths(&watched);
+        let canonical = dir.canonicalize().unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(paths.len(), 1);
+        assert_eq!(paths.get(canonical.as_path()), Some(&true));
+    }
+
+    #[test]
+    fn test_root_lost() {
+        let dir = env::temp_dir().join(format!("hbsi-watch-root-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let targets = [
+            WatchTarget::Directory(dir.clone()),
+            WatchTarget::File(dir.join("bundle.tar")),
+        ];
+        let watched: Vec<Watched> = targets
+            .iter()
+            .filter_map(|t| Watched::new(t, Scope::All).unwrap())
+            .collect();
+        let root = dir.canonicalize().unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        let moved = root.with_extension("old");
+        assert!(root_lost(&watched, &DebouncedEvent::Remove(root.clone())));
+        assert!(root_lost(
+            &watched,
+            &DebouncedEvent::NoticeRemove(root.clone())
+        ));
+        assert!(root_lost(&watched, &DebouncedEvent::Create(root.clone())));
+        assert!(root_lost(
+            &watched,
+            &DebouncedEvent::Rename(root.clone(), moved.clone())
+        ));
+        assert!(!root_lost(
+            &watched,
+            &DebouncedEvent::Remove(root.join("bundle.tar"))
+        ));
+        assert!(!root_lost(&watched, &DebouncedEvent::Rename(moved, root)));
+    }
+
+    #[test]
+    fn test_missed_notifications() {
+        let dir = env::temp_dir().join(format!("hbsi-watch-missed-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let mut hbse = HandlebarsEngine::new();
+        hbse.add(Box::new(DirectorySource::new(&dir, ".hbs")));
+        let target = WatchTarget::Directory(dir.clone());
+        let mut watch = Watch {
+            hbs: Arc::new(hbse),
+            options: WatchOptions::new()
+                .mode(WatchMode::Fallback(Duration::from_millis(50)))
+                .debounce(Duration::from_millis(50)),
+            watched: vec![Watched::new(&target, Scope::Source(0)).unwrap().unwrap()],
+            polled: Vec::new(),
+            stopped: Arc::new(AtomicBool::new(false)),
+            native: true,
+            next_scan: Instant::now(),
+        };
+        watch.scan(true);
+
+        // files that aren't templates don't count
+        fs::write(dir.join("notes.txt"), "notes").unwrap();
+        watch.scan(true);
+        assert!(watch.watched[0].unnotified.is_none());
+
+        // a change reported by a notification isn't missed
+        fs::write(dir.join("index.hbs"), "index").unwrap();
+        watch.scan(true);
+        watch.watched[0].notified = true;
+        thread::sleep(Duration::from_millis(120));
+        assert!(watch.scan(true).is_empty());
+        assert!(watch.native);
+
+        // a change still unreported after poll interval and debounce is
+        fs::write(dir.join("page.hbs"), "page").unwrap();
+        assert!(watch.scan(true).is_empty());
+        assert!(watch.scan(true).is_empty());
+        thread::sleep(Duration::from_millis(120));
+        let scopes = watch.scan(true);
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(scopes.len(), 1);
+        assert!(!watch.native);
     }
 }
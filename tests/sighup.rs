@@ -0,0 +1,56 @@
+// signal handlers are process wide, so these run in their own test binary
+#![cfg(all(unix, feature = "sighup"))]
+
+extern crate handlebars_iron as hbsi;
+
+use hbsi::handlebars::Handlebars;
+use hbsi::{HandlebarsEngine, SighupReloadable, Source, SourceError};
+use std::process;
+use std::sync::{mpsc, Arc, Mutex};
+use std::time::Duration;
+
+struct SharedSource(Arc<Mutex<String>>);
+
+impl Source for SharedSource {
+    fn load(&self, reg: &mut Handlebars) -> Result<(), SourceError> {
+        let tpl = self.0.lock().unwrap().clone();
+        reg.register_template_string("index", tpl)?;
+        Ok(())
+    }
+}
+
+fn sighup() {
+    let status = process::Command::new("kill")
+        .args(["-HUP", &process::id().to_string()])
+        .status()
+        .unwrap();
+    assert!(status.success());
+}
+
+#[test]
+fn test_reload_on_sighup() {
+    let template = Arc::new(Mutex::new("v1".to_owned()));
+    let mut hbse = HandlebarsEngine::new();
+    hbse.add(Box::new(SharedSource(template.clone())));
+    let (tx, rx) = mpsc::channel();
+    hbse.on_reload(move |event| {
+        let _ = tx.send(event.generation);
+    });
+    hbse.reload().unwrap();
+    assert_eq!(rx.recv().unwrap(), 1);
+    let hbse = Arc::new(hbse);
+    let handle = hbse.reload_on_sighup().unwrap();
+
+    *template.lock().unwrap() = "v2".to_owned();
+    sighup();
+    assert_eq!(rx.recv_timeout(Duration::from_secs(10)).unwrap(), 2);
+    assert_eq!(hbse.registry().render("index", &()).unwrap(), "v2");
+
+    // no more reloads once stopped
+    handle.stop();
+    handle.join().unwrap();
+    *template.lock().unwrap() = "v3".to_owned();
+    sighup();
+    assert!(rx.recv_timeout(Duration::from_millis(500)).is_err());
+    assert_eq!(hbse.registry().render("index", &()).unwrap(), "v2");
+}
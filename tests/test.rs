@@ -1,6 +1,21 @@
 extern crate handlebars_iron as hbsi;
+extern crate iron;
+#[cfg(feature = "archive")]
+extern crate tar;
 
-use hbsi::{DirectorySource, HandlebarsEngine};
+use hbsi::handlebars::{Context, Handlebars, Helper, Output, RenderContext, RenderError};
+use hbsi::{
+    Collision, CollisionPolicy, DirectorySource, ErrorPages, Fingerprint, HandlebarsEngine,
+    JsonNegotiation, MemorySource, OverlaySource, RenderFailureKind, Source, SourceError, Template,
+};
+use iron::prelude::*;
+use iron::{status, Listening};
+use std::collections::{BTreeMap, HashMap};
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::{env, fs, process};
 
 #[test]
 fn test_template() {
@@ -14,7 +29,7 @@ fn test_template() {
         panic!("{}", r);
     }
 
-    let hh = hbse.registry.read().unwrap();
+    let hh = hbse.registry();
 
     assert!(hh.get_template("index").is_some());
     assert!(hh.get_template("some/path/hello").is_some());
@@ -32,8 +47,920 @@ fn test_template_without_slash() {
         panic!("{}", r);
     }
 
-    let hh = hbse.registry.read().unwrap();
+    let hh = hbse.registry();
 
     assert!(hh.get_template("index").is_some());
     assert!(hh.get_template("some/path/hello").is_some());
 }
+
+#[test]
+fn test_reload_keeps_registry_on_failure() {
+    let mut hbse = HandlebarsEngine::new();
+    hbse.handlebars_mut().register_helper(
+        "ignore",
+        Box::new(
+            |_: &Helper,
+             _: &Handlebars,
+             _: &Context,
+             _: &mut RenderContext,
+             _: &mut dyn Output|
+             -> Result<(), RenderError> { Ok(()) },
+        ),
+    );
+
+    let mut good = BTreeMap::new();
+    good.insert("index".to_owned(), "{{ignore}}hello".to_owned());
+    hbse.add(Box::new(MemorySource(good)));
+    hbse.reload().expect("templates expected to load");
+
+    let mut bad = BTreeMap::new();
+    bad.insert("broken".to_owned(), "{{#if}}".to_owned());
+    hbse.add(Box::new(MemorySource(bad)));
+    assert!(hbse.reload().is_err());
+
+    let hh = hbse.registry();
+    assert!(hh.get_template("index").is_some());
+    assert!(hh.get_template("broken").is_none());
+    assert_eq!(hh.render("index", &()).unwrap(), "hello");
+}
+
+fn serve(chain: Chain) -> Listening {
+    Iron::new(chain).http("127.0.0.1:0").unwrap()
+}
+
+fn get(server: &Listening, path: &str) -> String {
+    let mut stream = TcpStream::connect(server.socket).unwrap();
+    write!(
+        stream,
+        "GET {} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n",
+        path
+    )
+    .unwrap();
+    let mut resp = String::new();
+    stream.read_to_string(&mut resp).unwrap();
+    resp
+}
+
+#[test]
+fn test_error_pages() {
+    let mut chain = Chain::new(|_: &mut Request| -> IronResult<Response> {
+        Err(IronError::new(io::Error::other("secret"), status::NotFound))
+    });
+    let mut templates = BTreeMap::new();
+    templates.insert(
+        "404".to_owned(),
+        "{{status}} {{reason}} {{error}}".to_owned(),
+    );
+    let mut hbse = HandlebarsEngine::new();
+    hbse.add(Box::new(MemorySource(templates)));
+    hbse.set_error_pages(Some(ErrorPages::new().page(status::NotFound, "404")));
+    hbse.reload().unwrap();
+    chain.link_after(hbse);
+
+    let mut server = serve(chain);
+    let resp = get(&server, "/");
+    server.close().unwrap();
+    assert!(resp.starts_with("HTTP/1.1 404 Not Found"));
+    assert!(resp.ends_with("404 Not Found "));
+}
+
+#[test]
+fn test_error_page_fallback() {
+    let mut chain = Chain::new(|_: &mut Request| -> IronResult<Response> {
+        Err(IronError::new(
+            io::Error::other("secret"),
+            status::BadRequest,
+        ))
+    });
+    let mut hbse = HandlebarsEngine::new();
+    hbse.set_error_pages(Some(ErrorPages::new().fallback("missing")));
+    chain.link_after(hbse);
+
+    let mut server = serve(chain);
+    let resp = get(&server, "/");
+    server.close().unwrap();
+    assert!(resp.starts_with("HTTP/1.1 400 Bad Request"));
+    assert!(resp.contains("Content-Type: text/plain"));
+    assert!(resp.ends_with("400 Bad Request"));
+}
+
+#[test]
+#[cfg(debug_assertions)]
+fn test_dev_error_page() {
+    let mut chain = Chain::new(|req: &mut Request| -> IronResult<Response> {
+        let name = req.url.path().join("/");
+        Ok(Response::with(Template::new(&name, "data").strict(true)))
+    });
+    let mut templates = BTreeMap::new();
+    templates.insert("index".to_owned(), "<p>\n{{missing}}\n</p>".to_owned());
+    let mut hbse = HandlebarsEngine::new();
+    hbse.add(Box::new(MemorySource(templates)));
+    hbse.set_dev_mode(true);
+    hbse.reload().unwrap();
+    chain.link_after(hbse);
+
+    let mut server = serve(chain);
+    let failed = get(&server, "/index");
+    let missing = get(&server, "/nothing");
+    server.close().unwrap();
+
+    assert!(failed.starts_with("HTTP/1.1 500 Internal Server Error"));
+    assert!(failed.contains("<mark>   2 | {{missing}}</mark>"));
+    assert!(failed.contains("&quot;data&quot;"));
+
+    assert!(missing.starts_with("HTTP/1.1 500 Internal Server Error"));
+    assert!(missing.contains("<li>index</li>"));
+}
+
+#[test]
+#[cfg(debug_assertions)]
+fn test_dev_error_page_collision() {
+    let mut chain = Chain::new(|_: &mut Request| -> IronResult<Response> {
+        Ok(Response::with(Template::new("index", "data").strict(true)))
+    });
+    let mut app = BTreeMap::new();
+    app.insert("index".to_owned(), "app {{missing}}".to_owned());
+    let mut plugin = BTreeMap::new();
+    plugin.insert("index".to_owned(), "plugin {{missing}}".to_owned());
+    let mut hbse = HandlebarsEngine::new();
+    hbse.add(Box::new(MemorySource(app)));
+    hbse.add(Box::new(MemorySource(plugin)));
+    hbse.set_collision_policy(CollisionPolicy::KeepFirst);
+    hbse.set_dev_mode(true);
+    hbse.reload().unwrap();
+    chain.link_after(hbse);
+
+    let mut server = serve(chain);
+    let resp = get(&server, "/");
+    server.close().unwrap();
+    assert!(resp.contains("<mark>   1 | app {{missing}}</mark>"));
+    assert!(!resp.contains("plugin"));
+}
+
+#[test]
+fn test_render_failure_status() {
+    let mut chain = Chain::new(|_: &mut Request| -> IronResult<Response> {
+        Ok(Response::with(Template::new("nothing", "data")))
+    });
+    let mut hbse = HandlebarsEngine::new();
+    hbse.set_failure_status(RenderFailureKind::MissingTemplate, status::NotFound);
+    chain.link_after(hbse);
+
+    let mut server = serve(chain);
+    let resp = get(&server, "/");
+    server.close().unwrap();
+    assert!(resp.starts_with("HTTP/1.1 404 Not Found"));
+}
+
+#[test]
+fn test_context_provider() {
+    use hbsi::handlebars::to_json;
+
+    let mut chain = Chain::new(|_: &mut Request| -> IronResult<Response> {
+        let mut data = BTreeMap::new();
+        data.insert("user", "handler");
+        Ok(Response::with((status::Ok, Template::new("index", data))))
+    });
+    let mut templates = BTreeMap::new();
+    templates.insert("index".to_owned(), "{{user}} {{site}} {{path}}".to_owned());
+    let mut hbse = HandlebarsEngine::new();
+    hbse.add(Box::new(MemorySource(templates)));
+    hbse.add_context_provider("user", Box::new(|_: &Request| to_json("provider")));
+    hbse.add_context_provider("site", Box::new(|_: &Request| to_json("first")));
+    hbse.add_context_provider("site", Box::new(|_: &Request| to_json("second")));
+    hbse.add_context_provider(
+        "path",
+        Box::new(|req: &Request| to_json(req.url.path().join("/"))),
+    );
+    hbse.reload().unwrap();
+    chain.link_after(hbse);
+
+    let mut server = serve(chain);
+    let resp = get(&server, "/about");
+    server.close().unwrap();
+    assert!(resp.starts_with("HTTP/1.1 200 OK"));
+    assert!(resp.ends_with("handler second about"));
+}
+
+#[test]
+fn test_json_negotiation_vary() {
+    use iron::headers::Vary;
+
+    let mut chain = Chain::new(|req: &mut Request| -> IronResult<Response> {
+        let mut resp = Response::with((status::Ok, Template::new("index", "data")));
+        match req.url.path()[0] {
+            "any" => resp.headers.set(Vary::Any),
+            _ => resp
+                .headers
+                .set_raw("Vary", vec![b"Accept-Encoding".to_vec()]),
+        }
+        Ok(resp)
+    });
+    let mut templates = BTreeMap::new();
+    templates.insert("index".to_owned(), "{{this}}".to_owned());
+    let mut hbse = HandlebarsEngine::new();
+    hbse.add(Box::new(MemorySource(templates)));
+    hbse.set_json_negotiation(Some(JsonNegotiation::default()));
+    hbse.reload().unwrap();
+    chain.link_after(hbse);
+
+    let mut server = serve(chain);
+    let encoding = get(&server, "/encoding");
+    let any = get(&server, "/any");
+    server.close().unwrap();
+    assert!(encoding.contains("Vary: Accept-Encoding\r\n"));
+    assert!(encoding.contains("Vary: Accept\r\n"));
+    assert!(any.contains("Vary: *\r\n"));
+    assert!(!any.contains("Vary: Accept"));
+}
+
+#[test]
+fn test_streaming() {
+    let mut chain = Chain::new(|req: &mut Request| -> IronResult<Response> {
+        let t = match req.url.path()[0] {
+            "page" => Template::new("index", "streamed"),
+            "content" => Template::with("<b>{{this}}</b>", "streamed"),
+            _ => Template::with("{{#if}}", "broken"),
+        };
+        Ok(Response::with((status::Ok, t)))
+    });
+    let mut templates = BTreeMap::new();
+    templates.insert("layout".to_owned(), "<p>{{{body}}}</p>".to_owned());
+    templates.insert("index".to_owned(), "{{this}}".to_owned());
+    let mut hbse = HandlebarsEngine::new();
+    hbse.add(Box::new(MemorySource(templates)));
+    hbse.set_default_layout(Some("layout"));
+    hbse.set_streaming(true);
+    hbse.reload().unwrap();
+    chain.link_after(hbse);
+
+    let mut server = serve(chain);
+    let page = get(&server, "/page");
+    let content = get(&server, "/content");
+    let broken = get(&server, "/broken");
+    server.close().unwrap();
+
+    assert!(page.starts_with("HTTP/1.1 200 OK"));
+    assert!(page.contains("Transfer-Encoding: chunked"));
+    assert!(page.contains("<p>streamed</p>"));
+    assert!(content.contains("Transfer-Encoding: chunked"));
+    assert!(content.contains("<p><b>streamed</b></p>"));
+    assert!(broken.starts_with("HTTP/1.1 500 Internal Server Error"));
+}
+
+/// a memory source shared with the test, counting templates it loads
+struct CountingSource {
+    templates: Arc<Mutex<BTreeMap<String, String>>>,
+    loaded: Arc<AtomicUsize>,
+}
+
+impl Source for CountingSource {
+    fn load(&self, reg: &mut Handlebars) -> Result<(), SourceError> {
+        let names: Vec<String> = self.templates.lock().unwrap().keys().cloned().collect();
+        for name in names {
+            self.load_template(&name, reg)?;
+        }
+        Ok(())
+    }
+
+    fn fingerprints(&self) -> Result<Option<HashMap<String, Fingerprint>>, SourceError> {
+        let templates = self.templates.lock().unwrap();
+        Ok(Some(
+            templates
+                .iter()
+                .map(|(name, tpl)| (name.clone(), Fingerprint::of_content(tpl)))
+                .collect(),
+        ))
+    }
+
+    fn load_template(&self, name: &str, reg: &mut Handlebars) -> Result<(), SourceError> {
+        self.loaded.fetch_add(1, Ordering::SeqCst);
+        let tpl = self.templates.lock().unwrap()[name].clone();
+        reg.register_template_string(name, tpl)?;
+        Ok(())
+    }
+
+    fn load_templates(
+        &self,
+        names: &[String],
+        reg: &mut Handlebars,
+    ) -> Vec<(Option<String>, SourceError)> {
+        names
+            .iter()
+            .filter_map(|name| {
+                self.load_template(name, reg)
+                    .err()
+                    .map(|e| (Some(name.clone()), e))
+            })
+            .collect()
+    }
+}
+
+#[test]
+fn test_incremental_reload() {
+    let templates = Arc::new(Mutex::new(BTreeMap::new()));
+    let loaded = Arc::new(AtomicUsize::new(0));
+    {
+        let mut t = templates.lock().unwrap();
+        t.insert("a".to_owned(), "a".to_owned());
+        t.insert("b".to_owned(), "b".to_owned());
+        t.insert("c".to_owned(), "c".to_owned());
+    }
+
+    let mut hbse = HandlebarsEngine::new();
+    hbse.add(Box::new(CountingSource {
+        templates: templates.clone(),
+        loaded: loaded.clone(),
+    }));
+    hbse.reload().unwrap();
+    assert_eq!(loaded.load(Ordering::SeqCst), 3);
+
+    hbse.reload().unwrap();
+    assert_eq!(loaded.load(Ordering::SeqCst), 3);
+
+    {
+        let mut t = templates.lock().unwrap();
+        t.insert("b".to_owned(), "changed".to_owned());
+        t.remove("c");
+        t.insert("d".to_owned(), "d".to_owned());
+    }
+    hbse.reload().unwrap();
+    assert_eq!(loaded.load(Ordering::SeqCst), 5);
+
+    let hh = hbse.registry();
+    assert_eq!(hh.render("a", &()).unwrap(), "a");
+    assert_eq!(hh.render("b", &()).unwrap(), "changed");
+    assert!(hh.get_template("c").is_none());
+    assert_eq!(hh.render("d", &()).unwrap(), "d");
+}
+
+#[test]
+fn test_incremental_reload_directory() {
+    let dir = env::temp_dir().join(format!("hbsi-incremental-{}", process::id()));
+    fs::create_dir_all(dir.join("sub")).unwrap();
+    fs::write(dir.join("index.hbs"), "index").unwrap();
+    fs::write(dir.join("sub/page.hbs"), "page").unwrap();
+
+    let mut hbse = HandlebarsEngine::new();
+    hbse.add(Box::new(DirectorySource::new(&dir, ".hbs")));
+    hbse.reload().unwrap();
+    assert_eq!(hbse.registry().render("sub/page", &()).unwrap(), "page");
+
+    fs::write(dir.join("index.hbs"), "index changed").unwrap();
+    fs::remove_file(dir.join("sub/page.hbs")).unwrap();
+    hbse.reload().unwrap();
+    fs::remove_dir_all(&dir).unwrap();
+
+    let hh = hbse.registry();
+    assert_eq!(hh.render("index", &()).unwrap(), "index changed");
+    assert!(hh.get_template("sub/page").is_none());
+}
+
+#[test]
+fn test_reload_report() {
+    let dir = env::temp_dir().join(format!("hbsi-report-{}", process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    fs::write(dir.join("good.hbs"), "good").unwrap();
+    fs::write(dir.join("bad.hbs"), "line\n{{#if x}}").unwrap();
+    fs::write(dir.join("worse.hbs"), "{{/each}}").unwrap();
+
+    let mut hbse = HandlebarsEngine::new();
+    hbse.add(Box::new(DirectorySource::new(&dir, ".hbs")));
+    hbse.set_reload_keep_going(true);
+    let report = hbse.reload().unwrap_err();
+    assert_eq!(report.sources.len(), 1);
+    assert_eq!(report.sources[0].loaded, vec!["good".to_owned()]);
+
+    let mut failed: Vec<_> = report.failures().collect();
+    failed.sort_by_key(|f| f.name.clone());
+    assert_eq!(failed.len(), 2);
+    assert_eq!(failed[0].name.as_deref(), Some("bad"));
+    assert_eq!(failed[0].path.as_ref(), Some(&dir.join("bad.hbs")));
+    assert!(failed[0].line.is_some());
+    assert_eq!(failed[1].name.as_deref(), Some("worse"));
+    assert!(hbse.registry().get_template("good").is_none());
+
+    fs::write(dir.join("bad.hbs"), "fixed").unwrap();
+    fs::remove_file(dir.join("worse.hbs")).unwrap();
+    let report = hbse.reload().unwrap();
+    fs::remove_dir_all(&dir).unwrap();
+    assert!(report.is_ok());
+    assert_eq!(report.sources[0].loaded.len(), 2);
+    assert_eq!(hbse.registry().render("bad", &()).unwrap(), "fixed");
+}
+
+fn colliding_engine(policy: CollisionPolicy) -> HandlebarsEngine {
+    let mut app = BTreeMap::new();
+    app.insert("index".to_owned(), "app".to_owned());
+    app.insert("about".to_owned(), "about".to_owned());
+    let mut plugin = BTreeMap::new();
+    plugin.insert("index".to_owned(), "plugin".to_owned());
+
+    let mut hbse = HandlebarsEngine::new();
+    hbse.add(Box::new(MemorySource(app)));
+    hbse.add(Box::new(MemorySource(plugin)));
+    hbse.set_collision_policy(policy);
+    hbse
+}
+
+#[test]
+fn test_collision_policy() {
+    let hbse = colliding_engine(CollisionPolicy::WarnOverride);
+    let report = hbse.reload().unwrap();
+    assert_eq!(report.collisions.len(), 1);
+    assert_eq!(hbse.registry().render("index", &()).unwrap(), "plugin");
+    assert_eq!(hbse.template_source("index"), Some(1));
+    assert_eq!(hbse.template_source("about"), Some(0));
+    assert_eq!(
+        hbse.collisions(),
+        vec![Collision {
+            name: "index".to_owned(),
+            sources: vec![0, 1],
+            used: Some(1),
+        }]
+    );
+
+    let hbse = colliding_engine(CollisionPolicy::KeepFirst);
+    hbse.reload().unwrap();
+    assert_eq!(hbse.registry().render("index", &()).unwrap(), "app");
+    assert_eq!(hbse.template_source("index"), Some(0));
+
+    let hbse = colliding_engine(CollisionPolicy::Error);
+    let report = hbse.reload().unwrap_err();
+    assert!(report.sources[0].failed.is_empty());
+    assert_eq!(report.sources[1].failed[0].name.as_deref(), Some("index"));
+    assert!(hbse.registry().get_template("index").is_none());
+    assert!(hbse.collisions().is_empty());
+}
+
+#[test]
+fn test_add_with_prefix() {
+    let mut app = BTreeMap::new();
+    app.insert("index".to_owned(), "app".to_owned());
+    let mut admin = BTreeMap::new();
+    admin.insert("index".to_owned(), "admin {{> list}}".to_owned());
+    admin.insert("list".to_owned(), "list".to_owned());
+
+    let mut hbse = HandlebarsEngine::new();
+    hbse.add(Box::new(MemorySource(app)));
+    hbse.add_with_prefix("admin/", Box::new(MemorySource(admin)));
+    let report = hbse.reload().unwrap();
+    assert!(report.collisions.is_empty());
+
+    let hh = hbse.registry();
+    assert_eq!(hh.render("index", &()).unwrap(), "app");
+    assert_eq!(hh.render("admin/index", &()).unwrap(), "admin list");
+    assert_eq!(hbse.template_source("admin/list"), Some(1));
+}
+
+#[test]
+fn test_add_with_prefix_new_partial() {
+    let templates = Arc::new(Mutex::new(BTreeMap::new()));
+    templates
+        .lock()
+        .unwrap()
+        .insert("index".to_owned(), "{{> item}}".to_owned());
+    let mut app = BTreeMap::new();
+    app.insert("item".to_owned(), "app item".to_owned());
+
+    let mut hbse = HandlebarsEngine::new();
+    hbse.add(Box::new(MemorySource(app)));
+    hbse.add_with_prefix(
+        "admin/",
+        Box::new(CountingSource {
+            templates: templates.clone(),
+            loaded: Arc::new(AtomicUsize::new(0)),
+        }),
+    );
+    hbse.reload().unwrap();
+    assert_eq!(
+        hbse.registry().render("admin/index", &()).unwrap(),
+        "app item"
+    );
+
+    // `index` didn't change, but its partial now refers to the new template
+    templates
+        .lock()
+        .unwrap()
+        .insert("item".to_owned(), "admin item".to_owned());
+    hbse.reload().unwrap();
+    assert_eq!(
+        hbse.registry().render("admin/index", &()).unwrap(),
+        "admin item"
+    );
+}
+
+#[test]
+fn test_overlay_source() {
+    let dir = env::temp_dir().join(format!("hbsi-overlay-{}", process::id()));
+    let (theme, base) = (dir.join("theme"), dir.join("base"));
+    fs::create_dir_all(&theme).unwrap();
+    fs::create_dir_all(&base).unwrap();
+    fs::write(base.join("index.hbs"), "base {{> header}}").unwrap();
+    fs::write(base.join("header.hbs"), "header").unwrap();
+    fs::write(theme.join("header.hbs"), "themed {{> super}}").unwrap();
+
+    let mut hbse = HandlebarsEngine::new();
+    hbse.add(Box::new(OverlaySource::new(
+        vec![theme.clone(), base.clone()],
+        ".hbs",
+    )));
+    hbse.reload().unwrap();
+    assert_eq!(
+        hbse.registry().render("index", &()).unwrap(),
+        "base themed header"
+    );
+
+    fs::write(base.join("header.hbs"), "new header").unwrap();
+    fs::write(theme.join("index.hbs"), "theme {{> super}}").unwrap();
+    hbse.reload().unwrap();
+    fs::remove_dir_all(&dir).unwrap();
+    assert_eq!(
+        hbse.registry().render("index", &()).unwrap(),
+        "theme base themed new header"
+    );
+}
+
+#[test]
+#[cfg(feature = "embed")]
+fn test_embed_templates() {
+    let mut hbse = HandlebarsEngine::new();
+    hbse.add(Box::new(hbsi::embed_templates!(
+        "examples/templates",
+        ".hbs"
+    )));
+    hbse.reload().unwrap();
+
+    let hh = hbse.registry();
+    assert!(hh.get_template("index").is_some());
+    assert!(hh.get_template("some/path/hello").is_some());
+}
+
+#[test]
+#[cfg(feature = "archive")]
+fn test_archive_source() {
+    use hbsi::ArchiveSource;
+
+    let path = env::temp_dir().join(format!("hbsi-archive-{}.tar", process::id()));
+    let write_archive = |files: &[(&str, &str)]| {
+        let mut builder = tar::Builder::new(fs::File::create(&path).unwrap());
+        for &(name, content) in files {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(content.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            builder
+                .append_data(&mut header, name, content.as_bytes())
+                .unwrap();
+        }
+        builder.finish().unwrap();
+    };
+    write_archive(&[
+        ("bundle/index.hbs", "{{> header}} index"),
+        ("bundle/header.hbs", "header"),
+        ("bundles/index.hbs", "other bundle"),
+    ]);
+
+    let mut hbse = HandlebarsEngine::new();
+    hbse.add(Box::new(ArchiveSource::new(&path, "bundle", ".hbs")));
+    hbse.reload().unwrap();
+    assert_eq!(
+        hbse.registry().render("index", &()).unwrap(),
+        "header index"
+    );
+
+    write_archive(&[("bundle/index.hbs", "{{#if}}")]);
+    let report = hbse.reload().unwrap_err();
+    fs::remove_file(&path).unwrap();
+    let failure = report.failures().next().unwrap();
+    assert_eq!(failure.name.as_deref(), Some("index"));
+    assert!(failure.path.is_none());
+    assert_eq!(
+        hbse.registry().render("index", &()).unwrap(),
+        "header index"
+    );
+}
+
+#[test]
+fn test_reload_sources() {
+    let first = Arc::new(Mutex::new(BTreeMap::new()));
+    let second = Arc::new(Mutex::new(BTreeMap::new()));
+    first
+        .lock()
+        .unwrap()
+        .insert("first".to_owned(), "1".to_owned());
+    second
+        .lock()
+        .unwrap()
+        .insert("second".to_owned(), "1".to_owned());
+
+    let mut hbse = HandlebarsEngine::new();
+    for templates in &[&first, &second] {
+        hbse.add(Box::new(CountingSource {
+            templates: Arc::clone(templates),
+            loaded: Arc::new(AtomicUsize::new(0)),
+        }));
+    }
+    hbse.reload().unwrap();
+
+    first
+        .lock()
+        .unwrap()
+        .insert("first".to_owned(), "2".to_owned());
+    second
+        .lock()
+        .unwrap()
+        .insert("second".to_owned(), "2".to_owned());
+    let report = hbse.reload_sources(&[1]).unwrap();
+    assert_eq!(report.sources[0].skipped, vec!["first".to_owned()]);
+    assert_eq!(report.sources[1].loaded, vec!["second".to_owned()]);
+
+    let hh = hbse.registry();
+    assert_eq!(hh.render("first", &()).unwrap(), "1");
+    assert_eq!(hh.render("second", &()).unwrap(), "2");
+}
+
+#[test]
+#[cfg(feature = "watch")]
+fn test_watch_lifecycle() {
+    use hbsi::{WatchOptions, Watchable};
+    use std::path::Path;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    let dir = env::temp_dir().join(format!("hbsi-watch-{}", process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let mut hbse = HandlebarsEngine::new();
+    hbse.add(Box::new(DirectorySource::new(&dir, ".hbs")));
+    hbse.reload().unwrap();
+    let hbse = Arc::new(hbse);
+
+    assert!(hbse.watch("./does/not/exist").is_err());
+    let watchable: &dyn Watchable = &hbse;
+    assert!(watchable
+        .watch_paths(&[Path::new("./does/not/exist")])
+        .is_err());
+
+    let options = WatchOptions::new().debounce(Duration::from_millis(50));
+    let handle = hbse.watch_sources_with(options).unwrap();
+    fs::write(dir.join("index.hbs"), "watched").unwrap();
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while hbse.registry().get_template("index").is_none() && Instant::now() < deadline {
+        thread::sleep(Duration::from_millis(50));
+    }
+    handle.stop();
+    handle.join().unwrap();
+    fs::remove_dir_all(&dir).unwrap();
+
+    assert_eq!(hbse.registry().render("index", &()).unwrap(), "watched");
+}
+
+#[test]
+#[cfg(feature = "watch")]
+fn test_watch_replaced_directory() {
+    use hbsi::{WatchOptions, Watchable};
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    let dir = env::temp_dir().join(format!("hbsi-replaced-{}", process::id()));
+    let new = dir.with_extension("new");
+    let old = dir.with_extension("old");
+    fs::create_dir_all(&dir).unwrap();
+    fs::create_dir_all(&new).unwrap();
+    fs::write(dir.join("index.hbs"), "v1").unwrap();
+    fs::write(new.join("index.hbs"), "replaced").unwrap();
+    let mut hbse = HandlebarsEngine::new();
+    hbse.add(Box::new(DirectorySource::new(&dir, ".hbs")));
+    hbse.reload().unwrap();
+    let hbse = Arc::new(hbse);
+
+    let options = WatchOptions::new()
+        .debounce(Duration::from_millis(50))
+        .retry_backoff(Duration::from_millis(10), Duration::from_millis(100));
+    let handle = hbse.watch_sources_with(options).unwrap();
+    // swap the directory like a deploy would
+    fs::rename(&dir, &old).unwrap();
+    fs::rename(&new, &dir).unwrap();
+    let deadline = Instant::now() + Duration::from_secs(10);
+    let rendered = || hbse.registry().render("index", &()).unwrap();
+    while rendered() != "replaced" && Instant::now() < deadline {
+        thread::sleep(Duration::from_millis(50));
+    }
+    // and later changes in the new directory are noticed too
+    fs::write(dir.join("page.hbs"), "page").unwrap();
+    while hbse.registry().get_template("page").is_none() && Instant::now() < deadline {
+        thread::sleep(Duration::from_millis(50));
+    }
+    handle.stop();
+    handle.join().unwrap();
+    fs::remove_dir_all(&dir).unwrap();
+    fs::remove_dir_all(&old).unwrap();
+
+    assert_eq!(rendered(), "replaced");
+    assert_eq!(hbse.registry().render("page", &()).unwrap(), "page");
+}
+
+#[test]
+#[cfg(feature = "watch")]
+fn test_watch_poll() {
+    use hbsi::{WatchMode, WatchOptions, Watchable};
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    let dir = env::temp_dir().join(format!("hbsi-poll-{}", process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let mut hbse = HandlebarsEngine::new();
+    hbse.add(Box::new(DirectorySource::new(&dir, ".hbs")));
+    hbse.reload().unwrap();
+    let hbse = Arc::new(hbse);
+
+    let options = WatchOptions::new().mode(WatchMode::Poll(Duration::from_millis(50)));
+    let handle = hbse.watch_sources_with(options).unwrap();
+    fs::write(dir.join("index.hbs"), "polled").unwrap();
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while hbse.registry().get_template("index").is_none() && Instant::now() < deadline {
+        thread::sleep(Duration::from_millis(50));
+    }
+    handle.stop();
+    handle.join().unwrap();
+    fs::remove_dir_all(&dir).unwrap();
+
+    assert_eq!(hbse.registry().render("index", &()).unwrap(), "polled");
+}
+
+#[test]
+fn test_poll() {
+    use hbsi::Pollable;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    let templates = Arc::new(Mutex::new(BTreeMap::new()));
+    templates
+        .lock()
+        .unwrap()
+        .insert("index".to_owned(), "v1".to_owned());
+    let mut hbse = HandlebarsEngine::new();
+    hbse.add(Box::new(CountingSource {
+        templates: templates.clone(),
+        loaded: Arc::new(AtomicUsize::new(0)),
+    }));
+    let hbse = Arc::new(hbse);
+
+    // the first reload doesn't wait for the interval
+    let handle = hbse.poll(Duration::from_secs(3600));
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while hbse.generation() == 0 && Instant::now() < deadline {
+        thread::sleep(Duration::from_millis(10));
+    }
+    assert_eq!(hbse.registry().render("index", &()).unwrap(), "v1");
+    handle.stop();
+    handle.join().unwrap();
+
+    templates
+        .lock()
+        .unwrap()
+        .insert("index".to_owned(), "v2".to_owned());
+    let handle = hbse.poll(Duration::from_millis(20));
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while hbse.generation() < 2 && Instant::now() < deadline {
+        thread::sleep(Duration::from_millis(10));
+    }
+    handle.stop();
+    handle.join().unwrap();
+    assert_eq!(hbse.registry().render("index", &()).unwrap(), "v2");
+}
+
+/// read from `stream` until `expected` arrives, returning all read text
+fn read_until(stream: &mut TcpStream, expected: &str) -> String {
+    let mut received = String::new();
+    let mut buf = [0; 1024];
+    while !received.contains(expected) {
+        let n = stream.read(&mut buf).unwrap();
+        assert!(n > 0, "stream closed, received {}", received);
+        received.push_str(&String::from_utf8_lossy(&buf[..n]));
+    }
+    received
+}
+
+#[test]
+#[cfg(debug_assertions)]
+fn test_live_reload() {
+    use iron::Handler;
+    use std::time::Duration;
+
+    let templates = Arc::new(Mutex::new(BTreeMap::new()));
+    templates
+        .lock()
+        .unwrap()
+        .insert("index".to_owned(), "<body>{{this}}</body>".to_owned());
+    let mut hbse = HandlebarsEngine::new();
+    hbse.add(Box::new(CountingSource {
+        templates: templates.clone(),
+        loaded: Arc::new(AtomicUsize::new(0)),
+    }));
+    hbse.set_live_reload(Some("/live"));
+    hbse.reload().unwrap();
+    let hbse = Arc::new(hbse);
+
+    let live = hbse.live_reload_handler().unwrap();
+    let mut chain = Chain::new(move |req: &mut Request| -> IronResult<Response> {
+        if req.url.path() == ["live"] {
+            live.handle(req)
+        } else {
+            Ok(Response::with(Template::new("index", "page")))
+        }
+    });
+    chain.link_after(hbse.clone());
+    let mut server = serve(chain);
+
+    let page = get(&server, "/");
+    assert!(page.contains("page<script>"));
+    assert!(page.contains("/live?since=1"));
+    assert!(page.ends_with("</script></body>"));
+
+    let mut stream = TcpStream::connect(server.socket).unwrap();
+    stream
+        .set_read_timeout(Some(Duration::from_secs(10)))
+        .unwrap();
+    write!(
+        stream,
+        "GET /live?since=1 HTTP/1.1\r\nHost: localhost\r\n\r\n"
+    )
+    .unwrap();
+    let head = read_until(&mut stream, "retry: 1000");
+    assert!(head.contains("Content-Type: text/event-stream"));
+    assert!(!head.contains("event:"));
+
+    templates
+        .lock()
+        .unwrap()
+        .insert("index".to_owned(), "{{#if}}".to_owned());
+    assert!(hbse.reload().is_err());
+    let failure = read_until(&mut stream, "\n\n");
+    assert!(failure.contains("event: failure\ndata: "));
+    assert!(failure.contains("index"));
+
+    templates
+        .lock()
+        .unwrap()
+        .insert("index".to_owned(), "<body>fixed</body>".to_owned());
+    hbse.reload().unwrap();
+    read_until(&mut stream, "event: reload\ndata: 2\n");
+
+    drop(stream);
+    server.close().unwrap();
+}
+
+#[test]
+fn test_reload_hooks() {
+    let templates = Arc::new(Mutex::new(BTreeMap::new()));
+    {
+        let mut t = templates.lock().unwrap();
+        t.insert("a".to_owned(), "a".to_owned());
+        t.insert("b".to_owned(), "b".to_owned());
+    }
+    let events = Arc::new(Mutex::new(Vec::new()));
+    let mut hbse = HandlebarsEngine::new();
+    hbse.add(Box::new(CountingSource {
+        templates: templates.clone(),
+        loaded: Arc::new(AtomicUsize::new(0)),
+    }));
+    // hooks can be added once the engine is shared
+    let hbse = Arc::new(hbse);
+    let received = events.clone();
+    hbse.on_reload(move |event| {
+        let changed: Vec<String> = event.changed.iter().cloned().collect();
+        let outcome = (event.generation, event.result.is_ok(), changed);
+        received.lock().unwrap().push(outcome);
+    });
+
+    hbse.reload().unwrap();
+    // nothing changed, the registry is kept
+    let registry = hbse.registry();
+    hbse.reload().unwrap();
+    assert!(Arc::ptr_eq(&registry, &hbse.registry()));
+    {
+        let mut t = templates.lock().unwrap();
+        t.insert("a".to_owned(), "changed".to_owned());
+        t.insert("c".to_owned(), "c".to_owned());
+        t.remove("b");
+    }
+    hbse.reload().unwrap();
+    templates
+        .lock()
+        .unwrap()
+        .insert("d".to_owned(), "{{#if}}".to_owned());
+    assert!(hbse.reload().is_err());
+    assert_eq!(hbse.generation(), 2);
+
+    let names = |names: &[&str]| names.iter().map(|n| n.to_string()).collect::<Vec<_>>();
+    assert_eq!(
+        *events.lock().unwrap(),
+        vec![
+            (1, true, names(&["a", "b"])),
+            (1, true, names(&[])),
+            (2, true, names(&["a", "b", "c"])),
+            (2, false, names(&[])),
+        ]
+    );
+}